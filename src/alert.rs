@@ -0,0 +1,152 @@
+/*
+ * Threshold rules for process resource usage. A rule is `<metric>> <limit>[:<duration>]`:
+ *   cpu>90%      - CPU usage is above 90%
+ *   mem>2G       - memory usage is above 2 GiB(suffixes K, M, G, T; without suffix - bytes)
+ *   cpu>90%:30s  - CPU usage stays above 90% for at least 30 seconds
+ */
+use std::time::{Duration, SystemTime};
+
+use crate::ux::parse_duration;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Metric {
+    Cpu,
+    Mem,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Rule {
+    pub(crate) text: String, // the rule as a user wrote it
+    pub(crate) metric: Metric,
+    pub(crate) limit: u64,     // CPU% or memory in bytes
+    pub(crate) hold: Duration, // how long the value must stay above the limit
+}
+
+// Parses memory size with an optional binary suffix: 512K, 1.5G, 100M
+pub(crate) fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_lowercase();
+    let s = s.strip_suffix("ib").or_else(|| s.strip_suffix('b')).unwrap_or(&s);
+    let (num, mul) = match s.chars().last()? {
+        'k' => (&s[..s.len() - 1], 1u64 << 10),
+        'm' => (&s[..s.len() - 1], 1 << 20),
+        'g' => (&s[..s.len() - 1], 1 << 30),
+        't' => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    let n = num.trim().parse::<f64>().ok()?;
+    if n < 0.0 {
+        return None;
+    }
+    Some((n * mul as f64).round() as u64)
+}
+
+impl Rule {
+    pub(crate) fn parse(s: &str) -> Result<Rule, String> {
+        let text = s.trim().to_string();
+        let (cond, hold) = match text.split_once(':') {
+            None => (text.as_str(), Duration::from_secs(0)),
+            Some((c, d)) => match parse_duration(d.trim()) {
+                Some(d) => (c, d),
+                None => return Err(format!("invalid duration '{}' in rule '{}'", d, text)),
+            },
+        };
+        let (name, limit) = match cond.split_once('>') {
+            None => return Err(format!("rule '{}' must be in format 'cpu>90%' or 'mem>2G'", text)),
+            Some((n, l)) => (n.trim().to_lowercase(), l.trim()),
+        };
+        let (metric, limit) = match name.as_str() {
+            "cpu" => (Metric::Cpu, limit.strip_suffix('%').unwrap_or(limit).trim().parse::<u64>().ok()),
+            "mem" => (Metric::Mem, parse_size(limit)),
+            _ => return Err(format!("unknown value '{}' in rule '{}'. Must be 'cpu' or 'mem'", name, text)),
+        };
+        match limit {
+            Some(limit) => Ok(Rule { text, metric, limit, hold }),
+            None => Err(format!("invalid limit in rule '{}'", text)),
+        }
+    }
+
+    // Returns the value of the process the rule is checked against
+    pub(crate) fn value(&self, cpu: u64, mem: u64) -> u64 {
+        match self.metric {
+            Metric::Cpu => cpu,
+            Metric::Mem => mem,
+        }
+    }
+}
+
+// State of a rule for a single process
+#[derive(Default, Clone)]
+pub(crate) struct Watch {
+    since: Option<SystemTime>, // when the value went above the limit
+    pub(crate) firing: bool,   // the rule has fired and the value is still above the limit
+}
+
+impl Watch {
+    // Checks a new value against the rule. Returns true when the rule fires: it fires once when the
+    // value has been above the limit long enough, and again only after the value drops.
+    pub(crate) fn check(&mut self, rule: &Rule, val: u64, tm: SystemTime) -> bool {
+        if val <= rule.limit {
+            self.since = None;
+            self.firing = false;
+            return false;
+        }
+        let since = *self.since.get_or_insert(tm);
+        if self.firing {
+            return false;
+        }
+        let held = tm.duration_since(since).unwrap_or_default();
+        self.firing = held >= rule.hold;
+        self.firing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("2K"), Some(2048));
+        assert_eq!(parse_size("1.5G"), Some(1_610_612_736));
+        assert_eq!(parse_size("512mb"), Some(512 << 20));
+        assert_eq!(parse_size("1GiB"), Some(1 << 30));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("-1M"), None);
+    }
+
+    #[test]
+    fn rule() {
+        let r = Rule::parse("mem>2G").unwrap();
+        assert_eq!((r.metric, r.limit, r.hold), (Metric::Mem, 2 << 30, Duration::from_secs(0)));
+        let r = Rule::parse(" CPU > 90% : 30s").unwrap();
+        assert_eq!((r.metric, r.limit, r.hold), (Metric::Cpu, 90, Duration::from_secs(30)));
+        assert_eq!(r.text, "CPU > 90% : 30s");
+        assert!(Rule::parse("cpu<90").is_err());
+        assert!(Rule::parse("io>10").is_err());
+        assert!(Rule::parse("cpu>x").is_err());
+        assert!(Rule::parse("cpu>90:x").is_err());
+    }
+
+    #[test]
+    fn watch() {
+        let r = Rule::parse("cpu>50:2s").unwrap();
+        let t = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let mut w = Watch::default();
+        assert!(!w.check(&r, 60, t(1)));
+        assert!(!w.check(&r, 60, t(2)));
+        assert!(w.check(&r, 60, t(3)));
+        // fires only once while the value stays above the limit
+        assert!(!w.check(&r, 70, t(4)));
+        assert!(w.firing);
+        assert!(!w.check(&r, 50, t(5)));
+        assert!(!w.firing);
+        assert!(!w.check(&r, 60, t(6)));
+        assert!(w.check(&r, 60, t(8)));
+
+        let r = Rule::parse("mem>1K").unwrap();
+        let mut w = Watch::default();
+        assert!(w.check(&r, 2048, t(1)));
+    }
+}
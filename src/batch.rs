@@ -0,0 +1,214 @@
+/*
+ * Non-interactive mode: the same sampling loop as the TUI uses, but every refresh prints one line
+ * per watched process to stdout
+ */
+use std::io::{self, stdout, ErrorKind, Write};
+use std::thread::sleep;
+use std::time::{Instant, SystemTime};
+
+use chrono::prelude::*;
+
+use crate::config::TitleMode;
+use crate::counter::Process;
+use crate::export::{format_time, json_str};
+use crate::layout::Layout;
+use crate::ux::{format_bytes, format_mem_bytes};
+
+fn print_header<W>(w: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(
+        w,
+        "{:19} {:>7} {:>5} {:>5} {:>6} {:>6} {:8} TITLE",
+        "TIME", "PID", "CPU%", "MEM", "READ", "WRITE", "STATE"
+    )
+}
+
+fn print_proc<W>(w: &mut W, tm: &str, proc: &Process, lay: &Layout) -> io::Result<()>
+where
+    W: Write,
+{
+    let state = if !proc.dead {
+        "alive".to_string()
+    } else if let Some(ref st) = proc.exit_status {
+        format!("exited({})", st)
+    } else {
+        "exited".to_string()
+    };
+    writeln!(
+        w,
+        "{} {:>7} {:>5} {:>5} {:>6} {:>6} {:8} {}",
+        tm,
+        proc.pid.to_string(),
+        proc.cpu.last(),
+        format_mem_bytes(proc.mem.last()),
+        format_bytes(proc.io_r_total),
+        format_bytes(proc.io_w_total),
+        state,
+        proc.description(lay.config.title_mode)
+    )
+}
+
+// Returns a JSON object with the latest sample of a process. Memory and I/O are in bytes.
+fn json_proc(proc: &Process, mode: TitleMode) -> String {
+    let status = match proc.exit_status {
+        None => "null".to_string(),
+        Some(ref st) => json_str(st),
+    };
+    format!(
+        "{{\"pid\":{},\"title\":{},\"cpu\":{},\"mem\":{},\"io_read\":{},\"io_write\":{},\"dead\":{},\"exit_status\":{}}}",
+        proc.pid,
+        json_str(&proc.description(mode)),
+        proc.cpu.last(),
+        proc.mem.last(),
+        proc.io_r_total * 1024,
+        proc.io_w_total * 1024,
+        proc.dead,
+        status
+    )
+}
+
+// Returns processes to print: all alive ones and the ones that have exited after the previous
+// sample was taken. `since` is the time of the previous sample(recorded time during replay).
+fn tick_procs(lay: &Layout, since: SystemTime) -> impl Iterator<Item = &Process> {
+    lay.procs.iter().filter(move |proc| {
+        let just_died = match proc.dead_since {
+            None => false,
+            Some(d) => d >= since,
+        };
+        !proc.dead || just_died
+    })
+}
+
+fn print_tick<W>(w: &mut W, lay: &Layout, since: SystemTime) -> io::Result<()>
+where
+    W: Write,
+{
+    let tm: DateTime<Local> = lay.sample_time().into();
+    let tm = tm.format("%Y-%m-%d %H:%M:%S").to_string();
+    for proc in tick_procs(lay, since) {
+        print_proc(w, &tm, proc, lay)?;
+    }
+    w.flush()
+}
+
+// Prints a single line with a JSON object: system totals and the processes
+fn print_json_tick<W>(w: &mut W, lay: &Layout, since: SystemTime) -> io::Result<()>
+where
+    W: Write,
+{
+    let procs: Vec<String> = tick_procs(lay, since).map(|p| json_proc(p, lay.config.title_mode)).collect();
+    writeln!(
+        w,
+        "{{\"timestamp\":{},\"cpu\":{},\"mem\":{},\"procs\":[{}]}}",
+        json_str(&format_time(lay.sample_time())),
+        lay.cpu_usage,
+        lay.mem_usage,
+        procs.join(",")
+    )?;
+    w.flush()
+}
+
+// Runs the sampling loop until the iteration or time limit is reached, the process started by
+// pwatch exits, or the recorded session ends. Closed stdout(e.g., `pwatch -b ... | head`) stops
+// the loop without an error.
+pub(crate) fn run(lay: &mut Layout) -> io::Result<()> {
+    let start = Instant::now();
+    let mut out = stdout();
+    let mut iteration = 0u64;
+    let res = if lay.config.json { Ok(()) } else { print_header(&mut out) };
+    if let Err(e) = res {
+        return if e.kind() == ErrorKind::BrokenPipe { Ok(()) } else { Err(e) };
+    }
+    loop {
+        let since = lay.sample_time();
+        lay.run_commands();
+        lay.update();
+        iteration += 1;
+        let res =
+            if lay.config.json { print_json_tick(&mut out, lay, since) } else { print_tick(&mut out, lay, since) };
+        if let Err(e) = res {
+            return if e.kind() == ErrorKind::BrokenPipe { Ok(()) } else { Err(e) };
+        }
+
+        if lay.child_finished() || lay.replay_finished() {
+            return Ok(());
+        }
+        if let Some(n) = lay.config.iterations {
+            if iteration >= n {
+                return Ok(());
+            }
+        }
+        let interval = lay.update_interval();
+        if let Some(d) = lay.config.duration {
+            if start.elapsed() + interval > d {
+                return Ok(());
+            }
+        }
+        sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter::Sample;
+    use sysinfo::{Pid, PidExt};
+
+    #[test]
+    fn json() {
+        let mut p = Process::new(Pid::from_u32(42), "a \"b\"".to_string(), String::new(), String::new());
+        let s = Sample { cpu: 5, mem: 4096, io_r_total: 1, io_w_total: 2, ..Sample::default() };
+        p.add_sample(&s, SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            json_proc(&p, TitleMode::Cmd),
+            "{\"pid\":42,\"title\":\"a \\\"b\\\"\",\"cpu\":0,\"mem\":4096,\"io_read\":1024,\"io_write\":2048,\"dead\":false,\"exit_status\":null}"
+        );
+        p.dead = true;
+        p.exit_status = Some("code 1".to_string());
+        assert!(json_proc(&p, TitleMode::Cmd).ends_with(",\"dead\":true,\"exit_status\":\"code 1\"}"));
+    }
+
+    #[test]
+    fn text() {
+        let lay = Layout::new(crate::config::Config::default());
+        let mut p = Process::new(Pid::from_u32(42), "sleep 9".to_string(), String::new(), String::new());
+        let s = Sample { cpu: 5, mem: 1024 * 1024, io_r_total: 2048, ..Sample::default() };
+        p.add_sample(&s, SystemTime::UNIX_EPOCH);
+        let mut out: Vec<u8> = Vec::new();
+        print_proc(&mut out, "2026-10-16 20:11:06", &p, &lay).unwrap();
+        // memory is in bytes, I/O is in KiB
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2026-10-16 20:11:06      42     0    1M  2.00M     0K alive    sleep 9\n"
+        );
+    }
+
+    #[test]
+    fn replayed_exit() {
+        let path = std::env::temp_dir().join(format!("pwatch-test-batch-{}.rec", std::process::id()));
+        let content = format!(
+            "{}\nT\t1000\t3\t40\nP\t1000\t42\tsrv\t\t\nS\t1000\t42\t10\t2048\t5\t0\t0\t0\n\
+             T\t3000\t4\t41\nD\t2500\t42\tcode 1\nT\t5000\t4\t41\n",
+            crate::record::HEADER
+        );
+        std::fs::write(&path, content).unwrap();
+        let replay = crate::record::Replay::load(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut lay = Layout::new(crate::config::Config::default());
+        lay.set_replay(replay);
+        let mut ticks = Vec::new();
+        for _ in 0..3 {
+            let since = lay.sample_time();
+            lay.update();
+            let mut out: Vec<u8> = Vec::new();
+            print_json_tick(&mut out, &lay, since).unwrap();
+            ticks.push(String::from_utf8(out).unwrap());
+        }
+        // the exited process is printed once, in the refresh it was recorded in
+        assert!(ticks[0].contains("\"dead\":false"));
+        assert!(ticks[1].contains("\"dead\":true,\"exit_status\":\"code 1\""));
+        assert!(ticks[2].ends_with("\"procs\":[]}\n"));
+    }
+}
@@ -1,10 +1,18 @@
 use std::env;
 use std::process::exit;
+use std::time::Duration;
 
 use getopts::{Matches, Options};
 use sysinfo::{Pid, PidExt};
 
+use crate::alert::Rule;
+use crate::statsd;
+use crate::summary;
+use crate::ux::parse_duration;
+
 const GRAPH_AREA: u16 = 5;
+// Available numbers of samples shown by a single graph column
+const ZOOMS: [usize; 11] = [1, 2, 5, 10, 30, 60, 120, 300, 600, 1800, 3600];
 
 // How CPU and memory graphs of the same process are displayed
 #[derive(PartialEq)]
@@ -46,18 +54,66 @@ pub(crate) enum GraphPosition {
     Top,   // One on top of another
 }
 
+// How several samples shown by a single graph column are reduced to one value
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum Reduce {
+    Max,
+    Avg,
+    Min,
+}
+
+// What a regular expression is matched against
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum MatchOn {
+    Exe,  // path to binary and binary name
+    Cmd,  // full command line
+    Name, // binary name
+    All,  // path to binary, binary name, and command line
+}
+
 pub(crate) struct Config {
     // TODO: pub(crate) pack: Pack,            // How to show CPU and MEM of the same process
     // TODO: pub(crate) no_cpu: bool,          // do not show CPU (unused yet)
     // TODO: pub(crate) no_mem: bool,          // do not show MEM (unused yet)
     pub(crate) pid_list: Vec<Pid>, // list of process PIDs provided by a user in command-line
-    pub(crate) filter: String,     // regular expression to filter process by their name/path to binary
+    pub(crate) filters: Vec<String>, // regular expressions to filter process by their name/path to binary
+    pub(crate) exclude: Vec<String>, // regular expressions to drop processes even if they match a filter
+    pub(crate) match_on: MatchOn,  // what filters and exclusions are matched against
+    pub(crate) user: Option<String>, // watch only processes owned by this user (name or UID)
+    pub(crate) pidfiles: Vec<String>, // files with PID of a process to watch, re-read at every refresh
+    pub(crate) cgroups: Vec<String>, // cgroup directories: all processes of a cgroup are watched
     pub(crate) detail: Detail,     // Graph details (set of characters used to display graphs)
     pub(crate) scale_max: bool, // How to scale MEM graph: true - from 0 ro all-time max, false - from displayed min to max
     pub(crate) freq: u64,       // process stats refresh rate in range 0.25s .. 10s
     pub(crate) title_mode: TitleMode, // what use for a process title when displaying it
     pub(crate) graphs: Graph,
     pub(crate) graph_pos: GraphPosition,
+    pub(crate) zoom: usize,                 // number of samples shown by a single graph column
+    pub(crate) reduce: Reduce,              // how samples of a graph column are reduced to one value
+    pub(crate) spawn: Vec<String>,          // command line of a process that pwatch starts itself and watches
+    pub(crate) children: bool,              // watch descendants of selected processes as well
+    pub(crate) tree: bool,                  // show a selected process and all its descendants as a single process
+    pub(crate) stitch: bool,                // continue graphs of a dead process when it is restarted
+    pub(crate) batch: bool,                 // print samples to stdout instead of drawing graphs
+    pub(crate) iterations: Option<u64>,     // batch mode: stop after N refreshes
+    pub(crate) duration: Option<Duration>,  // batch mode: stop after this time
+    pub(crate) record: Option<String>,      // file to write all samples to
+    pub(crate) replay: Option<String>,      // recorded session to show instead of live data
+    pub(crate) export: Option<String>,      // file to write the history of all processes to on exit
+    pub(crate) history: Duration,           // how long samples are kept for scrolling graphs back and export
+    pub(crate) fail_if: Vec<Rule>,          // peak values that make pwatch exit with an error
+    pub(crate) alerts: Vec<Rule>,           // thresholds to alert about
+    pub(crate) log: Option<String>,         // file to write alerts and other events to
+    pub(crate) on_exit: Option<String>,     // shell command to run when a watched process exits
+    pub(crate) on_alert: Option<String>,    // shell command to run when an alert fires
+    pub(crate) leak_warn: Option<Duration>, // warn when memory of a process keeps growing for this time
+    pub(crate) summary: Option<summary::Format>, // print a report about all processes on exit
+    pub(crate) listen: Option<String>,      // address to serve Prometheus metrics at
+    pub(crate) json: bool,                  // batch mode prints a JSON object every refresh instead of text
+    pub(crate) statsd: Option<String>,      // address of StatsD daemon to send metrics to
+    pub(crate) statsd_prefix: String,       // template of metric names: {pid} and {title} are replaced
+    pub(crate) control: Option<String>,     // Unix socket to receive commands from scripts
+    pub(crate) unwatched: Vec<Pid>,         // processes removed by a control command: they are never selected again
 }
 
 impl Default for Config {
@@ -68,17 +124,54 @@ impl Default for Config {
             // TODO: no_mem: false,
             pid_list: Vec::new(),
             detail: Detail::High,
-            filter: String::new(),
+            filters: Vec::new(),
+            exclude: Vec::new(),
+            match_on: MatchOn::Exe,
+            pidfiles: Vec::new(),
+            cgroups: Vec::new(),
+            user: None,
             scale_max: false,
             freq: 1_000,
             title_mode: TitleMode::Cmd,
             graphs: Graph::All,
             graph_pos: GraphPosition::Auto,
+            zoom: 1,
+            reduce: Reduce::Max,
+            spawn: Vec::new(),
+            children: false,
+            tree: false,
+            stitch: false,
+            batch: false,
+            iterations: None,
+            duration: None,
+            record: None,
+            replay: None,
+            export: None,
+            history: Duration::from_secs(3600),
+            fail_if: Vec::new(),
+            alerts: Vec::new(),
+            log: None,
+            on_exit: None,
+            on_alert: None,
+            leak_warn: None,
+            summary: None,
+            listen: None,
+            json: false,
+            statsd: None,
+            statsd_prefix: statsd::DEFAULT_PREFIX.to_string(),
+            control: None,
+            unwatched: Vec::new(),
         }
     }
 }
 
 impl Config {
+    // Returns the number of samples collected during the history retention time if samples are
+    // taken every `interval` milliseconds
+    pub(crate) fn history_len(&self, interval: u64) -> usize {
+        (self.history.as_millis() / interval.max(1) as u128) as usize
+    }
+
     // Returns the number of used non-empty characters for graphs
     pub(crate) fn steps(&self) -> u16 {
         match self.detail {
@@ -88,6 +181,11 @@ impl Config {
         }
     }
 
+    // Returns the number of PIDs, pidfiles, cgroups, and regular expressions used to select processes
+    pub(crate) fn selector_count(&self) -> usize {
+        self.pid_list.len() + self.filters.len() + self.pidfiles.len() + self.cgroups.len()
+    }
+
     pub(crate) fn switch_title_type(&mut self) {
         let old = self.title_mode;
         self.title_mode = match old {
@@ -115,6 +213,25 @@ impl Config {
         };
     }
 
+    // Shows more(zoom out) or fewer(zoom in) samples in a graph column. Returns true if the zoom
+    // has changed.
+    pub(crate) fn change_zoom(&mut self, zoom_out: bool) -> bool {
+        let idx = ZOOMS.iter().position(|z| *z == self.zoom).unwrap_or(0);
+        let idx = if zoom_out { (idx + 1).min(ZOOMS.len() - 1) } else { idx.saturating_sub(1) };
+        let old = self.zoom;
+        self.zoom = ZOOMS[idx];
+        self.zoom != old
+    }
+
+    pub(crate) fn switch_reduce(&mut self) {
+        let old = self.reduce;
+        self.reduce = match old {
+            Reduce::Max => Reduce::Avg,
+            Reduce::Avg => Reduce::Min,
+            Reduce::Min => Reduce::Max,
+        };
+    }
+
     pub(crate) fn min_graph_height(&self) -> u16 {
         if self.graph_pos == GraphPosition::Top && self.graphs == Graph::All {
             // 2 graphs with +/-, title, IO
@@ -177,11 +294,33 @@ impl Config {
     }
 }
 
+// Adds a free command-line argument to the list of process selectors. If the argument contains
+// only digits and commas, it is a list of PIDs. Otherwise, it is a regular expression.
+fn add_selector(conf: &mut Config, arg: &str) {
+    let is_pid = arg.chars().all(|c| c.is_numeric() || c == ',');
+    if is_pid {
+        for pd in arg.split(',') {
+            if let Ok(i) = pd.parse::<u32>() {
+                conf.pid_list.push(Pid::from_u32(i));
+            }
+        }
+    } else {
+        conf.filters.push(arg.to_string());
+    }
+}
+
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} NAME|PID [options]", program);
+    let brief =
+        format!("Usage: {0} NAME|PID [NAME|PID ...] [options]\n       {0} [options] -- COMMAND [ARGS]", program);
     print!("{}", opts.usage(&brief));
 }
 
+// Returns the index of "--" that separates the command to start and watch. A "--" can be a value
+// of an option, e.g. `--statsd-prefix --`, so the separator is the first one with valid options before it.
+fn spawn_separator(opts: &Options, args: &[String]) -> Option<usize> {
+    (1..args.len()).filter(|&i| args[i] == "--").find(|&i| opts.parse(&args[1..i]).is_ok())
+}
+
 pub(crate) fn parse_args() -> Config {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
@@ -195,8 +334,65 @@ pub(crate) fn parse_args() -> Config {
     opts.optopt("s", "scale", "Memory graph scaling mode", "zero | min");
     opts.optopt("t", "title", "Set process title", "name | path | cmd");
     opts.optopt("g", "graphs", "Select which graphs to show", "all | mem | cpu");
+    opts.optmulti("", "exclude", "Do not watch processes matching this regex (can be repeated)", "REGEX");
+    opts.optopt("m", "match", "What regular expressions are matched against", "exe | cmd | name | all");
+    opts.optopt("u", "user", "Watch only processes owned by the user", "NAME | UID");
+    opts.optmulti("", "pidfile", "Watch the process which PID is in the file (can be repeated)", "PATH");
+    opts.optmulti("", "cgroup", "Watch all processes of the cgroup (can be repeated)", "PATH");
+    opts.optflag("", "children", "Watch child processes of selected processes, including the ones started later");
+    opts.optflag("", "tree", "Show combined usage of a selected process and all its descendants");
+    opts.optflag("b", "batch", "Print samples to stdout, one line per process every refresh, instead of graphs");
+    opts.optflag("", "json", "Batch mode: print one JSON object per refresh instead of text lines");
+    opts.optopt("n", "iterations", "Stop after N refreshes (turns on batch mode)", "N");
+    opts.optopt("d", "duration", "Stop after this time (turns on batch mode)", "DURATION (e.g., 90, 30s, 5m, 2h)");
+    opts.optopt("", "record", "Write all samples to a file for later replay or analysis", "FILE");
+    opts.optopt("", "replay", "Show a recorded session instead of live data", "FILE");
+    opts.optopt(
+        "",
+        "export",
+        "Write history of all processes to a file on exit: JSON for *.json, CSV otherwise",
+        "FILE",
+    );
+    opts.optopt("", "history", "Keep samples for this time to scroll graphs back (default 1h)", "DURATION");
+    opts.optmulti(
+        "",
+        "alert",
+        "Alert when a value exceeds the limit (can be repeated)",
+        "RULE (e.g., mem>2G, cpu>90%:30s)",
+    );
+    opts.optmulti(
+        "",
+        "fail-if",
+        "Exit with code 3 if a peak value of a process exceeds the limit (can be repeated)",
+        "RULE (e.g., mem>1.5G, cpu>90%)",
+    );
+    opts.optopt("", "log", "Write alerts to a file", "FILE");
+    opts.optopt("", "leak-warn", "Warn when memory trend of a process stays positive for this time", "DURATION");
+    opts.optopt("", "on-exit", "Run a shell command when a watched process exits", "COMMAND");
+    opts.optopt("", "on-alert", "Run a shell command when an alert fires", "COMMAND");
+    opts.optopt("", "listen", "Serve Prometheus metrics of watched processes at http://ADDR/metrics", "ADDR");
+    opts.optopt("", "statsd", "Send metrics of watched processes to StatsD every refresh", "HOST:PORT");
+    opts.optopt(
+        "",
+        "statsd-prefix",
+        "Template of StatsD metric names: {pid} and {title} are replaced (default pwatch.{pid})",
+        "TEMPLATE",
+    );
+    opts.optopt("", "control", "Receive commands from scripts on a Unix domain socket", "PATH");
+    opts.optflagopt("", "summary", "Print a table with usage of all processes on exit", "text | md");
+    opts.optflag("", "stitch", "Continue graphs of a dead process when a process with the same command line starts");
+
+    let spawn_at = spawn_separator(&opts, &args);
+    if let Some(pos) = spawn_at {
+        conf.spawn = args[pos + 1..].to_vec();
+        if conf.spawn.is_empty() {
+            eprintln!("Command to start is missing after '--'");
+            print_usage(&program, &opts);
+            exit(1);
+        }
+    }
 
-    let matches: Matches = match opts.parse(&args[1..]) {
+    let matches: Matches = match opts.parse(&args[1..spawn_at.unwrap_or(args.len())]) {
         Ok(m) => m,
         Err(e) => {
             eprintln!("{}", e);
@@ -210,11 +406,121 @@ pub(crate) fn parse_args() -> Config {
         println!("PWatch Version {}", version);
         exit(0);
     }
-    if matches.opt_present("h") || matches.free.is_empty() {
+    let names = &matches.free;
+    conf.replay = matches.opt_str("replay");
+    conf.pidfiles = matches.opt_strs("pidfile");
+    conf.cgroups = matches.opt_strs("cgroup");
+    let no_selectors = names.is_empty() && conf.pidfiles.is_empty() && conf.cgroups.is_empty();
+    if matches.opt_present("h") || (no_selectors && conf.spawn.is_empty() && conf.replay.is_none()) {
         print_usage(&program, &opts);
         exit(0);
     }
 
+    conf.exclude = matches.opt_strs("exclude");
+    conf.user = matches.opt_str("u");
+    conf.children = matches.opt_present("children");
+    conf.tree = matches.opt_present("tree");
+    if conf.tree && conf.children {
+        eprintln!("Options --tree and --children cannot be used together: --tree already includes all descendants");
+        print_usage(&program, &opts);
+        exit(1);
+    }
+    conf.stitch = matches.opt_present("stitch");
+    conf.json = matches.opt_present("json");
+    // Limits make sense only without TUI: a user stops TUI with a key
+    conf.batch = matches.opt_present("batch") || conf.json || matches.opt_present("n") || matches.opt_present("d");
+    conf.record = matches.opt_str("record");
+    if conf.record.is_some() && conf.replay.is_some() {
+        eprintln!("Options --record and --replay cannot be used together: replayed samples are not recorded");
+        print_usage(&program, &opts);
+        exit(1);
+    }
+    conf.export = matches.opt_str("export");
+    if let Some(v) = matches.opt_str("n") {
+        match v.parse::<u64>() {
+            Ok(n) if n > 0 => conf.iterations = Some(n),
+            _ => {
+                eprintln!("Invalid value '{}' for iterations. Must be a positive number", v);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    if let Some(v) = matches.opt_str("d") {
+        match parse_duration(&v) {
+            Some(d) => conf.duration = Some(d),
+            None => {
+                eprintln!("Invalid value '{}' for duration", v);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    if let Some(v) = matches.opt_str("history") {
+        match parse_duration(&v) {
+            Some(d) => conf.history = d,
+            None => {
+                eprintln!("Invalid value '{}' for history", v);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    for v in matches.opt_strs("fail-if") {
+        match Rule::parse(&v) {
+            Ok(r) if !r.hold.is_zero() => {
+                eprintln!("Invalid budget: duration is not supported in '{}'", r.text);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+            Ok(r) => conf.fail_if.push(r),
+            Err(e) => {
+                eprintln!("Invalid budget: {}", e);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    for v in matches.opt_strs("alert") {
+        match Rule::parse(&v) {
+            Ok(r) => conf.alerts.push(r),
+            Err(e) => {
+                eprintln!("Invalid alert: {}", e);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    conf.log = matches.opt_str("log");
+    if let Some(v) = matches.opt_str("leak-warn") {
+        match parse_duration(&v) {
+            Some(d) => conf.leak_warn = Some(d),
+            None => {
+                eprintln!("Invalid value '{}' for leak-warn", v);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    conf.on_exit = matches.opt_str("on-exit");
+    conf.on_alert = matches.opt_str("on-alert");
+    conf.listen = matches.opt_str("listen");
+    conf.statsd = matches.opt_str("statsd");
+    conf.control = matches.opt_str("control");
+    if let Some(v) = matches.opt_str("statsd-prefix") {
+        conf.statsd_prefix = v;
+    }
+    if matches.opt_present("summary") {
+        conf.summary = match matches.opt_str("summary").as_deref() {
+            None | Some("text") => Some(summary::Format::Text),
+            Some("md") => Some(summary::Format::Markdown),
+            Some(v) => {
+                eprintln!("Invalid value '{}' for summary. Must be 'text' or 'md'", v);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
     if let Some(val) = matches.opt_str("q") {
         conf.detail = match val.as_str() {
             "high" => Detail::High,
@@ -229,13 +535,7 @@ pub(crate) fn parse_args() -> Config {
     }
     if let Some(v) = matches.opt_str("r") {
         if let Ok(n) = v.parse::<u64>() {
-            conf.freq = if n < 250 {
-                250
-            } else if n > 10_000 {
-                10_000
-            } else {
-                n
-            };
+            conf.freq = n.clamp(250, 10_000);
         }
     }
 
@@ -264,6 +564,20 @@ pub(crate) fn parse_args() -> Config {
         }
     }
 
+    if let Some(t) = matches.opt_str("m") {
+        conf.match_on = match t.as_str() {
+            "exe" => MatchOn::Exe,
+            "cmd" => MatchOn::Cmd,
+            "name" => MatchOn::Name,
+            "all" => MatchOn::All,
+            _ => {
+                eprintln!("Invalid value '{}' for match. Must be one of 'exe', 'cmd', 'name', and 'all'", t);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+
     if let Some(t) = matches.opt_str("g") {
         conf.graphs = match t.as_str() {
             "all" => Graph::All,
@@ -277,17 +591,38 @@ pub(crate) fn parse_args() -> Config {
         }
     }
 
-    let names = &matches.free[0];
-    let is_pid = names.chars().all(|c| c.is_numeric() || c == ',');
-    if is_pid {
-        for pd in names.split(',') {
-            if let Ok(i) = pd.parse::<u32>() {
-                conf.pid_list.push(Pid::from_u32(i));
-            }
-        }
-    } else {
-        conf.filter = names.to_string();
+    for arg in names.iter() {
+        add_selector(&mut conf, arg);
     }
 
     conf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separator() {
+        let mut opts = Options::new();
+        opts.optopt("", "prefix", "", "TEMPLATE");
+        opts.optflag("b", "batch", "");
+        let args = |s: &str| -> Vec<String> { s.split(' ').map(|a| a.to_string()).collect() };
+        assert_eq!(spawn_separator(&opts, &args("pwatch -b -- sleep 1")), Some(2));
+        assert_eq!(spawn_separator(&opts, &args("pwatch --prefix -- -b -- sleep 1")), Some(4));
+        assert_eq!(spawn_separator(&opts, &args("pwatch --prefix -- -b 42")), None);
+        assert_eq!(spawn_separator(&opts, &args("pwatch -b 42")), None);
+    }
+
+    #[test]
+    fn selectors() {
+        let mut conf = Config::default();
+        for arg in ["1234", "postgres", "10,20,", "redis-server"].iter() {
+            add_selector(&mut conf, arg);
+        }
+        let pids: Vec<Pid> = [1234, 10, 20].iter().map(|p| Pid::from_u32(*p)).collect();
+        assert_eq!(conf.pid_list, pids);
+        assert_eq!(conf.filters, vec!["postgres".to_string(), "redis-server".to_string()]);
+        assert_eq!(conf.selector_count(), 5);
+    }
+}
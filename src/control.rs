@@ -0,0 +1,214 @@
+/*
+ * Control socket(option --control, Unix only). Scripts connect to a Unix domain socket and send
+ * commands, one per line. Every command gets a reply line: `ok` or `error <description>`.
+ *   watch PID      - start watching a process
+ *   unwatch PID    - stop watching a process and remove it from the screen
+ *   mark           - set or clear the mark, like hotkey `Space`
+ *   screenshot     - save the screen to a file, like hotkey `F2`
+ *   export [FILE]  - export the history of all processes to a file, like hotkey `F3`
+ * Commands are executed by the main loop at the next refresh.
+ */
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+
+use sysinfo::{Pid, PidExt};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Watch(Pid),
+    Unwatch(Pid),
+    Mark,
+    Screenshot,
+    Export(Option<String>),
+}
+
+impl Command {
+    pub(crate) fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            None => (line.to_lowercase(), ""),
+            Some((n, a)) => (n.to_lowercase(), a.trim()),
+        };
+        let pid = || match arg.parse::<u32>() {
+            Ok(pid) => Ok(Pid::from_u32(pid)),
+            Err(_) => Err(format!("'{}' requires a PID", name)),
+        };
+        match name.as_str() {
+            "" => Err("empty command".to_string()),
+            "watch" => Ok(Command::Watch(pid()?)),
+            "unwatch" => Ok(Command::Unwatch(pid()?)),
+            "mark" | "screenshot" if !arg.is_empty() => Err(format!("'{}' does not have arguments", name)),
+            "mark" => Ok(Command::Mark),
+            "screenshot" => Ok(Command::Screenshot),
+            "export" if arg.is_empty() => Ok(Command::Export(None)),
+            "export" => Ok(Command::Export(Some(arg.to_string()))),
+            _ => Err(format!("unknown command '{}'", name)),
+        }
+    }
+}
+
+// A command received from a client. The client waits for the reply.
+pub(crate) struct Request {
+    pub(crate) cmd: Command,
+    reply: Sender<String>,
+}
+
+impl Request {
+    // Sends the result of the command to the client: `ok` with optional details or `error` with the reason
+    pub(crate) fn reply(self, res: Result<String, String>) {
+        let msg = match res {
+            Ok(s) if s.is_empty() => "ok".to_string(),
+            Ok(s) => format!("ok {}", s),
+            Err(e) => format!("error {}", e),
+        };
+        // The client may have disconnected already
+        let _ = self.reply.send(msg);
+    }
+}
+
+pub(crate) struct Control {
+    rx: Receiver<Request>,
+    path: PathBuf,
+}
+
+impl Control {
+    // Creates the socket and starts accepting clients in a background thread
+    #[cfg(unix)]
+    pub(crate) fn start(path: &str) -> io::Result<Control> {
+        use std::fs;
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        // A socket left by a crashed pwatch is removed, but a socket of a running one is kept
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "the file already exists"));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let tx = tx.clone();
+                thread::spawn(move || serve(stream, tx));
+            }
+        });
+        Ok(Control { rx, path: PathBuf::from(path) })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn start(_path: &str) -> io::Result<Control> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "control socket is supported only on Unix"))
+    }
+
+    // Returns commands received since the last call
+    pub(crate) fn pending(&self) -> Vec<Request> {
+        self.rx.try_iter().collect()
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Reads commands from a client, passes them to the main loop, and sends replies back
+#[cfg(unix)]
+fn serve(stream: std::os::unix::net::UnixStream, tx: Sender<Request>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::mpsc::channel;
+
+    let mut out = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = match Command::parse(&line) {
+            Err(e) => format!("error {}", e),
+            Ok(cmd) => {
+                let (reply, rx) = channel();
+                if tx.send(Request { cmd, reply }).is_err() {
+                    return;
+                }
+                rx.recv().unwrap_or_else(|_| "error pwatch is exiting".to_string())
+            }
+        };
+        if writeln!(out, "{}", msg).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse("watch 42"), Ok(Command::Watch(Pid::from_u32(42))));
+        assert_eq!(Command::parse(" UNWATCH  7 "), Ok(Command::Unwatch(Pid::from_u32(7))));
+        assert_eq!(Command::parse("mark"), Ok(Command::Mark));
+        assert_eq!(Command::parse("screenshot"), Ok(Command::Screenshot));
+        assert_eq!(Command::parse("export"), Ok(Command::Export(None)));
+        assert_eq!(Command::parse("export my file.json"), Ok(Command::Export(Some("my file.json".to_string()))));
+        assert!(Command::parse("watch").is_err());
+        assert!(Command::parse("watch x").is_err());
+        assert!(Command::parse("mark 1").is_err());
+        assert!(Command::parse("kill 1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("pwatch-test-control-{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let ctl = Control::start(&path).unwrap();
+        // the socket is busy while pwatch is running
+        assert!(Control::start(&path).is_err());
+
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut s = UnixStream::connect(&path).unwrap();
+                s.write_all(b"watch 42\nbogus\n").unwrap();
+                let mut lines = BufReader::new(s).lines();
+                (lines.next().unwrap().unwrap(), lines.next().unwrap().unwrap())
+            })
+        };
+        let mut reqs = Vec::new();
+        for _ in 0..250 {
+            reqs = ctl.pending();
+            if !reqs.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(reqs.len(), 1);
+        let req = reqs.pop().unwrap();
+        assert_eq!(req.cmd, Command::Watch(Pid::from_u32(42)));
+        req.reply(Ok(String::new()));
+        let (first, second) = client.join().unwrap();
+        assert_eq!(first, "ok");
+        assert_eq!(second, "error unknown command 'bogus'");
+
+        drop(ctl);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
@@ -1,16 +1,21 @@
 #![allow(clippy::suspicious_map)]
 
 use std::cmp::{Eq, Ordering};
+use std::collections::HashMap;
 use std::io::Write;
 use std::time::{Duration, SystemTime};
-use std::u64;
 
 use crossterm::{cursor, queue, style, style::Color, Result};
 use sysinfo::Pid;
 use unicode_width::UnicodeWidthStr;
 
-use crate::config::{Config, Detail, Graph, TitleMode};
-use crate::ux::{fade_str_left, format_bytes, format_diff, format_duration, format_mem, round_to_hundred, short_round};
+use crate::alert::{Rule, Watch};
+use crate::config::{Config, Detail, Graph, Reduce, TitleMode};
+use crate::stats::{Running, Stats};
+use crate::ux::{
+    cut_string, fade_str_left, format_bytes, format_diff_bytes, format_duration, format_mem_bytes, round_to_hundred,
+    short_round,
+};
 
 // set of charcters for different graph detalizations
 const LOW: [char; 2] = [' ', '\u{2588}'];
@@ -33,13 +38,16 @@ struct DrawVal {
 
 // A single counter to manage stream of data
 pub(crate) struct Counter {
-    pub(crate) values: Vec<u64>,        // last values
+    values: Vec<u64>,                   // last values, may include a few older than needed (see `trim`)
     pub(crate) display_cnt: usize,      // number or last items to show
-    pub(crate) max: u64,                // all time max
-    pub(crate) scale_to: u64,           // scale to this value if auto_scale == false
-    pub(crate) auto_scale: bool,        // scale to max in range or to max_val
+    pub(crate) keep: usize, // number of last values to keep for scrolling back (at least display_cnt are kept)
+    pub(crate) max: u64,    // all time max
+    pub(crate) scale_to: u64, // scale to this value if auto_scale == false
+    pub(crate) auto_scale: bool, // scale to max in range or to max_val
     pub(crate) mark_value: Option<u64>, // value when a user pressed a key to mark the position
-    w: u16,                             // width and height of graph area
+    pub(crate) count: u64,  // total number of values added
+    pub(crate) restarts: Vec<u64>, // indices(in terms of `count`) of the first values after process restarts
+    w: u16,                 // width and height of graph area
     h: u16,
     pub(crate) screen: Vec<char>, // precalculated graph: WxH
     pub(crate) gmin: u64,         // range of the graphic
@@ -51,6 +59,7 @@ impl Default for Counter {
         Counter {
             values: Vec::new(),
             display_cnt: 40,
+            keep: 0,
             scale_to: 0,
             max: 0,
             auto_scale: false,
@@ -60,6 +69,8 @@ impl Default for Counter {
             gmin: 0,
             gmax: 0,
             mark_value: None,
+            count: 0,
+            restarts: Vec::new(),
         }
     }
 }
@@ -68,29 +79,93 @@ impl Counter {
     // Add a new measurement for the value. Automatically updates the running maximum and cleans up
     // values that goes out of sight.
     pub(crate) fn add(&mut self, val: u64) {
+        self.count += 1;
         if val > self.max {
             self.max = val;
         }
         if self.scale_to != 0 && self.scale_to < val {
             self.scale_to = round_to_hundred(val);
         }
-        let l = self.values.len();
-        if l == 0 || l < self.display_cnt {
-            self.values.push(val);
-            return;
+        self.values.push(val);
+        trim(&mut self.values, self.display_cnt.max(self.keep));
+    }
+
+    // Returns the values kept for displaying and scrolling back
+    pub(crate) fn kept(&self) -> &[u64] {
+        let keep = self.display_cnt.max(self.keep);
+        &self.values[self.values.len().saturating_sub(keep)..]
+    }
+
+    // Returns values shown by a graph of a given width that is scrolled back by `offset` values.
+    // The offset is limited, so the graph is filled if there are enough values. Every column
+    // shows `conf.zoom` values reduced to one. Columns are aligned to the total number of added
+    // values, so they do not change when new values arrive. Every value is paired with a flag
+    // whether the column includes the first value after a process restart.
+    fn columns(&self, width: usize, offset: usize, conf: &Config) -> Vec<(u64, bool)> {
+        let zoom = conf.zoom.max(1);
+        let values = self.kept();
+        let l = values.len();
+        let mut end = l - offset.min(l.saturating_sub(width * zoom));
+        // index of the first kept value in terms of `count`
+        let base = self.count - l as u64;
+        let mut cols = Vec::with_capacity(width);
+        while end > 0 && cols.len() < width {
+            let last = base + end as u64 - 1;
+            let col_start = last - last % zoom as u64;
+            let begin = if col_start > base { (col_start - base) as usize } else { 0 };
+            let val = reduce(&values[begin..end], conf.reduce);
+            let restart = self.restarts.iter().any(|r| *r >= base + begin as u64 && *r < base + end as u64);
+            cols.push((val, restart));
+            end = begin;
         }
-        for idx in 0..l - 1 {
-            self.values[idx] = self.values[idx + 1];
+        cols.reverse();
+        cols
+    }
+
+    // Returns kept values added since the latest restart
+    fn since_restart(&self) -> &[u64] {
+        let values = self.kept();
+        let base = self.count - values.len() as u64;
+        let first = match self.restarts.last() {
+            Some(r) if *r > base => (*r - base) as usize,
+            _ => 0,
+        };
+        &values[first..]
+    }
+
+    // Returns the slope of linear regression over values kept since the latest restart: the
+    // average change of the value per refresh
+    pub(crate) fn slope(&self) -> Option<f64> {
+        let vs = self.since_restart();
+        if vs.len() < 3 {
+            return None;
         }
-        self.values[l - 1] = val;
+        let n = vs.len() as f64;
+        let avg_x = (n - 1.0) / 2.0;
+        let avg_y = vs.iter().map(|v| *v as f64).sum::<f64>() / n;
+        let mut num = 0.0f64;
+        let mut den = 0.0f64;
+        for (x, y) in vs.iter().enumerate() {
+            let dx = x as f64 - avg_x;
+            num += dx * (*y as f64 - avg_y);
+            den += dx * dx;
+        }
+        Some(num / den)
+    }
+
+    // Remembers that the next value is the first one after the process restart
+    fn mark_restart(&mut self) {
+        self.restarts.push(self.count);
+    }
+
+    // Returns true if the next value is the first one after the process restart
+    fn restart_pending(&self) -> bool {
+        self.restarts.last() == Some(&self.count)
     }
 
     // Returns the current value
     pub(crate) fn last(&self) -> u64 {
-        if self.values.is_empty() {
-            return 0;
-        }
-        self.values[self.values.len() - 1]
+        self.values.last().copied().unwrap_or(0)
     }
 
     // Returns the change since the previous measurement
@@ -101,18 +176,14 @@ impl Counter {
         }
         let last = self.values[l - 1] as i64;
         let prev = if let Some(p) = self.mark_value { p } else { self.values[l - 2] };
-        last as i64 - prev as i64
+        last - prev as i64
     }
 
     // Returns the maximum value from last N measurements
     pub(crate) fn max_last_n(&self, n: usize) -> u64 {
         let mut max = 0u64;
-        let vs: &[u64] = if n >= self.values.len() {
-            &self.values
-        } else {
-            let l = self.values.len();
-            &self.values[l - n..]
-        };
+        let values = self.kept();
+        let vs: &[u64] = if n >= values.len() { values } else { &values[values.len() - n..] };
         if let Some(m) = vs.iter().max() {
             max = *m;
         }
@@ -121,9 +192,11 @@ impl Counter {
 
     // Updates internal "screen" for faster output to terminal. The function "draws" graph of a
     // given dimensions in memory array.
-    pub(crate) fn update(&mut self, neww: u16, newh: u16, conf: &Config) {
+    pub(crate) fn update(&mut self, neww: u16, newh: u16, offset: usize, conf: &Config) {
         if self.w != neww || self.h != newh {
             self.screen = vec![' '; neww as usize * (newh + 1) as usize];
+            self.w = neww;
+            self.h = newh;
         } else {
             let _c = self.screen.iter_mut().map(|c| *c = ' ').count();
         }
@@ -145,19 +218,18 @@ impl Counter {
         if scale_to == 0 {
             return;
         }
-        let l = self.values.len();
-        let vs = if l <= max_w { &self.values } else { &self.values[l - max_w..] };
-        let mut start = if l <= neww as usize { neww - (l as u16) } else { 0 };
+        let cols = self.columns(max_w, offset, conf);
+        let start = max_w - cols.len();
 
         let step = scale_to as f64 / newh as f64;
         let mut prev: u64 = u64::MAX;
-        for v in vs.iter() {
+        for (idx, (v, restart)) in cols.iter().enumerate() {
             let delta = if self.auto_scale { *v - scale_min } else { *v };
             let val = if delta > scale_to { scale_to as f64 } else { delta as f64 };
             let full = (val / step).trunc() as u16;
             let part = (val - (full as f64) * step) / step;
 
-            let xx = start as usize;
+            let xx = start + idx;
             for yy in 0..full {
                 let pos = xx + (newh - yy - 1) as usize * neww as usize;
                 self.screen[pos] = '\u{2588}';
@@ -167,7 +239,9 @@ impl Counter {
                 let pos = xx + (newh - full - 1) as usize * neww as usize;
                 self.screen[pos] = c;
             }
-            let ch = if prev == u64::MAX || prev == *v {
+            let ch = if *restart {
+                'R'
+            } else if prev == u64::MAX || prev == *v {
                 ' '
             } else if prev > *v {
                 '-'
@@ -176,7 +250,6 @@ impl Counter {
             };
             let pos = xx + (newh) as usize * neww as usize;
             self.screen[pos] = ch;
-            start += 1;
             prev = *v;
         }
     }
@@ -185,13 +258,14 @@ impl Counter {
     // so the displayed min and max in the picture are exact values. Min is always rounded down,
     // and max is always rounded up. For better looking graphs, if rounded min and max are the
     // same, the max is increased by one.
-    pub(crate) fn calculate_range(&mut self) {
+    pub(crate) fn calculate_range(&mut self, width: usize, offset: usize, conf: &Config) {
         if !self.auto_scale || self.values.is_empty() {
             return;
         }
-        let mut min = self.values[0];
+        let cols = self.columns(width, offset, conf);
+        let mut min = cols[0].0;
         let mut max = 0;
-        for v in self.values.iter() {
+        for (v, _) in cols.iter() {
             if min > *v {
                 min = *v;
             }
@@ -229,15 +303,92 @@ impl Counter {
     }
 }
 
+// Drops the oldest items when there are twice as many items as must be kept. Dropping them in
+// batches avoids moving all kept items at every refresh.
+fn trim<T>(items: &mut Vec<T>, keep: usize) {
+    if items.len() > 2 * keep {
+        let extra = items.len() - keep;
+        items.drain(..extra);
+    }
+}
+
+// Time, PID, and I/O of a sample kept in the history of a process. CPU and MEM of the sample are
+// kept by the process counters.
+#[derive(Clone, Copy)]
+struct Stamp {
+    tm: SystemTime,
+    pid: Pid, // a process revived by stitching gets a new PID, so the old samples keep the PID they were taken from
+    io_r_total: u64,
+    io_w_total: u64,
+    io_r_delta: u64,
+    io_w_delta: u64,
+}
+
+// Resource usage of a process at the moment of refresh
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Sample {
+    pub(crate) cpu: u64,
+    pub(crate) mem: u64,
+    pub(crate) io_r_total: u64,
+    pub(crate) io_w_total: u64,
+    pub(crate) io_r_delta: u64,
+    pub(crate) io_w_delta: u64,
+}
+
+// A process tree: a root process and all its descendants that are displayed as a single process
+#[derive(Default)]
+pub(crate) struct Tree {
+    pub(crate) members: HashMap<Pid, Sample>, // the latest samples of alive members
+    io_r_gone: u64,                           // total IO of members that have exited
+    io_w_gone: u64,
+}
+
+impl Tree {
+    // Replaces member samples with fresh ones and returns the combined usage of the tree. Members
+    // that have exited since the previous refresh are still counted with their last sample.
+    pub(crate) fn tick(&mut self, fresh: HashMap<Pid, Sample>) -> Sample {
+        let mut total = Sample::default();
+        for (pid, s) in self.members.iter() {
+            if fresh.contains_key(pid) {
+                continue;
+            }
+            total.cpu += s.cpu;
+            total.mem += s.mem;
+            self.io_r_gone += s.io_r_total;
+            self.io_w_gone += s.io_w_total;
+        }
+        let mut members = HashMap::with_capacity(fresh.len());
+        for (pid, mut s) in fresh.into_iter() {
+            if !self.members.contains_key(&pid) {
+                // The first CPU value is really CPU usage in milliseconds, not %, so skip it
+                s.cpu = 0;
+            }
+            total.cpu += s.cpu;
+            total.mem += s.mem;
+            total.io_r_total += s.io_r_total;
+            total.io_w_total += s.io_w_total;
+            total.io_r_delta += s.io_r_delta;
+            total.io_w_delta += s.io_w_delta;
+            members.insert(pid, s);
+        }
+        total.io_r_total += self.io_r_gone;
+        total.io_w_total += self.io_w_gone;
+        self.members = members;
+        total
+    }
+}
+
 pub(crate) struct Process {
-    pub(crate) cpu: Counter,  // CPU history
-    pub(crate) mem: Counter,  // MEM history
-    pub(crate) pid: Pid,      // process PID
-    pub(crate) dead: bool,    // whether process is active
-    pub(crate) cmd: String,   // process command line
-    pub(crate) exe: String,   // process command line
-    pub(crate) title: String, // process command line
-    pub(crate) x: u16,        // box coordinates to draw all counters
+    pub(crate) cpu: Counter,     // CPU history
+    pub(crate) mem: Counter,     // MEM history
+    pub(crate) pid: Pid,         // process PID
+    pub(crate) dead: bool,       // whether process is active
+    pub(crate) cmd: String,      // process command line
+    pub(crate) exe: String,      // process command line
+    pub(crate) title: String,    // process command line
+    pub(crate) selector: String, // PID or regular expression that has brought the process in
+    pub(crate) restarts: usize,  // how many times the process was restarted (see option --stitch)
+    pub(crate) x: u16,           // box coordinates to draw all counters
     pub(crate) y: u16,
     pub(crate) w: u16,
     pub(crate) h: u16,
@@ -246,9 +397,23 @@ pub(crate) struct Process {
     pub(crate) io_r_total: u64, // total IO read since start
     pub(crate) io_w_delta: u64, // IO write since last check
     pub(crate) io_r_delta: u64, // IO read since last check
+    io_w_prev: u64,             // total IO write of previous runs of a restarted process
+    io_r_prev: u64,             // total IO read of previous runs of a restarted process
     pub(crate) dead_since: Option<SystemTime>, // Time when the process has exited (or been interrupted)
+    pub(crate) first_seen: Option<SystemTime>, // time of the first sample
+    pub(crate) exit_status: Option<String>, // exit code or signal (known only for a process started by pwatch)
+    pub(crate) tree: Option<Tree>, // set if the process is displayed with all its descendants
+    history: Vec<Stamp>,        // time, PID, and IO of samples, kept as long as graph values
+    pub(crate) alerts: Vec<Watch>, // state of every alert rule from the config
+    pub(crate) exit_handled: bool, // the exit has been written to the record and reported to hooks
+    pub(crate) growing_since: Option<SystemTime>, // when memory trend has become positive
+    pub(crate) leak_warned: bool, // memory has been growing longer than allowed by option --leak-warn
+    pub(crate) dropping: bool,  // the regular expression being edited in the prompt would remove the process
     mark_r_io: Option<u64>,     // value when a user pressed a key to mark the position
     mark_w_io: Option<u64>,     // value when a user pressed a key to mark the position
+    mark_at: Option<u64>,       // the number of values added before a user pressed a key to mark the position
+    session: (Running, Running), // CPU and MEM statistics of the whole session
+    since_mark: (Running, Running), // CPU and MEM statistics since the mark
 }
 
 impl PartialEq for Process {
@@ -303,16 +468,32 @@ impl Process {
             h: 0,
             io_w_total: 0,
             io_r_total: 0,
+            io_w_prev: 0,
+            io_r_prev: 0,
             io_w_delta: 0,
             io_r_delta: 0,
             dead_since: None,
+            first_seen: None,
+            exit_status: None,
+            tree: None,
+            history: Vec::new(),
+            alerts: Vec::new(),
+            exit_handled: false,
+            growing_since: None,
+            leak_warned: false,
+            dropping: false,
             mark_r_io: None,
             mark_w_io: None,
+            mark_at: None,
+            session: Default::default(),
+            since_mark: Default::default(),
             sided: false,
             pid,
             cmd,
             exe,
             title,
+            selector: String::new(),
+            restarts: 0,
         };
         p.cpu.scale_to = 100;
         p.mem.auto_scale = true;
@@ -343,13 +524,156 @@ impl Process {
         self.mem.display_cnt = mm_w as usize;
     }
     pub(crate) fn add(&mut self, cpu: u64, mem: u64) {
-        if self.cpu.values.is_empty() {
+        if self.cpu.values.is_empty() || self.cpu.restart_pending() {
             // The first CPU value is really CPU usage in milliseconds, not %, so skip it
             self.cpu.add(0);
         } else {
             self.cpu.add(cpu);
+            self.session.0.add(cpu);
+            self.since_mark.0.add(cpu);
         }
         self.mem.add(mem);
+        self.session.1.add(mem);
+        self.since_mark.1.add(mem);
+    }
+    pub(crate) fn add_sample(&mut self, s: &Sample, tm: SystemTime) {
+        // IO deltas of the first sample are the totals, so keep them empty. After a restart, the
+        // deltas of the previous process must not be shown either.
+        let first = self.mem.values.is_empty() || self.mem.restart_pending();
+        let (io_r_delta, io_w_delta) = if first { (0, 0) } else { (s.io_r_delta, s.io_w_delta) };
+        self.io_r_delta = io_r_delta;
+        self.io_w_delta = io_w_delta;
+        self.add(s.cpu, s.mem);
+        self.io_r_total = s.io_r_total;
+        self.io_w_total = s.io_w_total;
+
+        if self.first_seen.is_none() {
+            self.first_seen = Some(tm);
+        }
+        let (io_r_total, io_w_total) = (s.io_r_total, s.io_w_total);
+        self.history.push(Stamp { tm, pid: self.pid, io_r_total, io_w_total, io_r_delta, io_w_delta });
+        trim(&mut self.history, self.cpu.display_cnt.max(self.mem.display_cnt).max(self.mem.keep));
+    }
+    // Returns kept samples with their time and PID, oldest first. CPU and MEM of the samples are
+    // taken from the counters.
+    pub(crate) fn samples(&self) -> impl Iterator<Item = (SystemTime, Pid, Sample)> + '_ {
+        let (cpu, mem) = (self.cpu.kept(), self.mem.kept());
+        let n = self.history.len().min(cpu.len()).min(mem.len());
+        let stamps = &self.history[self.history.len() - n..];
+        let values = cpu[cpu.len() - n..].iter().zip(mem[mem.len() - n..].iter());
+        stamps.iter().zip(values).map(|(st, (cpu, mem))| {
+            let s = Sample {
+                cpu: *cpu,
+                mem: *mem,
+                io_r_total: st.io_r_total,
+                io_w_total: st.io_w_total,
+                io_r_delta: st.io_r_delta,
+                io_w_delta: st.io_w_delta,
+            };
+            (st.tm, st.pid, s)
+        })
+    }
+    // Checks the latest values against alert rules. Returns indices of the rules that have fired.
+    pub(crate) fn check_alerts(&mut self, rules: &[Rule], tm: SystemTime) -> Vec<usize> {
+        if self.alerts.len() != rules.len() {
+            self.alerts = vec![Watch::default(); rules.len()];
+        }
+        let (cpu, mem) = (self.cpu.last(), self.mem.last());
+        let mut fired = Vec::new();
+        for (idx, (rule, watch)) in rules.iter().zip(self.alerts.iter_mut()).enumerate() {
+            if watch.check(rule, rule.value(cpu, mem), tm) {
+                fired.push(idx);
+            }
+        }
+        fired
+    }
+    // Resets the state of all alert rules, e.g. after the process has exited. Returns indices of the
+    // rules that were firing.
+    pub(crate) fn clear_alerts(&mut self) -> Vec<usize> {
+        let firing = self.alerts.iter().enumerate().filter(|(_, w)| w.firing).map(|(idx, _)| idx).collect();
+        self.alerts.iter_mut().for_each(|w| *w = Watch::default());
+        firing
+    }
+    // Returns the memory trend in bytes per hour. The slope per refresh is scaled by the average
+    // time between the samples it is calculated over: it differs from the refresh interval during
+    // replay or when refreshes are late.
+    pub(crate) fn mem_trend(&self) -> Option<f64> {
+        let slope = self.mem.slope()?;
+        let n = self.mem.since_restart().len().min(self.history.len());
+        let span = &self.history[self.history.len() - n..];
+        let (first, last) = (span.first()?.tm, span.last()?.tm);
+        let ms = last.duration_since(first).ok()?.as_millis() as f64;
+        if n < 2 || ms == 0.0 {
+            return None;
+        }
+        Some(slope * 3_600_000.0 * (n - 1) as f64 / ms)
+    }
+    // Returns the peak CPU and MEM of the whole session. Unlike the graph maximums, they are not
+    // reset by hotkey `r`.
+    pub(crate) fn peak(&self) -> (u64, u64) {
+        (self.session.0.max, self.session.1.max)
+    }
+    // Returns the average CPU and MEM of the whole session, without the first CPU sample that is not
+    // a real usage value
+    pub(crate) fn session_avg(&self) -> (f64, f64) {
+        (self.session.0.avg(), self.session.1.avg())
+    }
+    // Returns total IO read and write of the whole session, including all runs of a restarted process
+    pub(crate) fn session_io(&self) -> (u64, u64) {
+        (self.io_r_prev + self.io_r_total, self.io_w_prev + self.io_w_total)
+    }
+    // Returns the rules which limits the peak values of the process have exceeded
+    pub(crate) fn over_budget<'a>(&self, rules: &'a [Rule]) -> Vec<&'a Rule> {
+        let (cpu, mem) = self.peak();
+        rules.iter().filter(|r| r.value(cpu, mem) > r.limit).collect()
+    }
+    // Returns statistics of CPU and memory usage since the mark, or for the whole session if the
+    // mark is not set
+    pub(crate) fn stats(&self) -> (Option<Stats>, Option<Stats>) {
+        let (cpu, mem) = if self.mark_at.is_some() { &self.since_mark } else { &self.session };
+        (cpu.stats(), mem.stats())
+    }
+    // Checks whether memory has been growing for the given time. Returns true when the warning
+    // should be issued: only once until the memory stops growing.
+    pub(crate) fn check_leak(&mut self, window: Duration, tm: SystemTime) -> bool {
+        match self.mem.slope() {
+            Some(slope) if slope > 0.0 => {
+                let since = *self.growing_since.get_or_insert(tm);
+                if self.leak_warned || tm.duration_since(since).unwrap_or_default() < window {
+                    return false;
+                }
+                self.leak_warned = true;
+                true
+            }
+            _ => {
+                self.growing_since = None;
+                self.leak_warned = false;
+                false
+            }
+        }
+    }
+    // Sets how many last values are kept for scrolling graphs back
+    pub(crate) fn set_keep(&mut self, keep: usize) {
+        self.cpu.keep = keep;
+        self.mem.keep = keep;
+    }
+    // Attaches a restarted process to this dead one: the graphs continue with a restart marker
+    pub(crate) fn restart(&mut self, pid: Pid) {
+        self.pid = pid;
+        self.dead = false;
+        self.dead_since = None;
+        self.exit_status = None;
+        self.exit_handled = false;
+        self.restarts += 1;
+        self.cpu.mark_restart();
+        self.mem.mark_restart();
+        self.io_r_prev += self.io_r_total;
+        self.io_w_prev += self.io_w_total;
+        // IO counters of the new process start from zero
+        if self.mark_r_io.is_some() {
+            self.mark_r_io = Some(0);
+            self.mark_w_io = Some(0);
+        }
     }
     pub(crate) fn toggle_mark(&mut self) {
         self.mem.toggle_mark();
@@ -357,9 +681,12 @@ impl Process {
         if is_off {
             self.mark_r_io = Some(self.io_r_total);
             self.mark_w_io = Some(self.io_w_total);
+            self.mark_at = Some(self.cpu.count);
+            self.since_mark = Default::default();
         } else {
             self.mark_r_io = None;
             self.mark_w_io = None;
+            self.mark_at = None;
         }
     }
     pub(crate) fn reset_max(&mut self) {
@@ -369,7 +696,7 @@ impl Process {
 
     // Returns title for the process. A user defines the default displayed field, but the function
     // may select another field if the selected one is empty.
-    fn description(&self, mode: TitleMode) -> String {
+    pub(crate) fn description(&self, mode: TitleMode) -> String {
         let mut desc = match mode {
             TitleMode::Cmd => self.cmd.to_string(),
             TitleMode::Exe => self.exe.to_string(),
@@ -388,6 +715,16 @@ impl Process {
     }
 }
 
+// Reduces several values to one
+fn reduce(vs: &[u64], how: Reduce) -> u64 {
+    match how {
+        Reduce::Max => vs.iter().copied().max().unwrap_or(0),
+        Reduce::Min => vs.iter().copied().min().unwrap_or(0),
+        Reduce::Avg if vs.is_empty() => 0,
+        Reduce::Avg => vs.iter().sum::<u64>() / vs.len() as u64,
+    }
+}
+
 // Returns the character to print for a value:
 // >=1.0 - the entire block is filled
 // 0.0..1.0 - means the area that should be filled rounded up.
@@ -407,7 +744,14 @@ fn char_for_value(val: f64, conf: &Config) -> char {
     }
 }
 
-fn draw_spikes<W>(w: &mut W, cnt: &Counter, rect: DrawRect, xshift: u16, dt: Option<SystemTime>) -> Result<()>
+fn draw_spikes<W>(
+    w: &mut W,
+    cnt: &Counter,
+    rect: DrawRect,
+    xshift: u16,
+    exited: Option<Duration>,
+    status: Option<&str>,
+) -> Result<()>
 where
     W: Write,
 {
@@ -423,9 +767,14 @@ where
         queue!(w, cursor::MoveTo(rect.x, rect.y + yy), style::Print(&s))?;
     }
 
-    if let Some(d) = dt {
-        let since = if let Ok(elapsed) = d.elapsed() { elapsed } else { Duration::from_secs(0) };
-        let st = format!("Exited {} ago", format_duration(since));
+    if let Some(since) = exited {
+        let mut st = format!("Exited {} ago", format_duration(since));
+        if let Some(status) = status {
+            let full = format!("{} ({})", st, status);
+            if full.width() <= rect.w as usize {
+                st = full;
+            }
+        }
         let wd = st.width();
         let diff = rect.w as usize - wd; // TODO: can st be longer than width?
         let pad = " ".repeat(diff);
@@ -449,6 +798,8 @@ where
                 queue!(w, style::SetForegroundColor(Color::Red), style::Print(c))?;
             } else if *c == '-' {
                 queue!(w, style::SetForegroundColor(Color::Green), style::Print(c))?;
+            } else if *c == 'R' {
+                queue!(w, style::SetForegroundColor(Color::Yellow), style::Print(c))?;
             } else {
                 queue!(w, style::Print(" "))?;
             }
@@ -486,22 +837,30 @@ where
     Ok(())
 }
 
-fn draw_mem_head<W>(w: &mut W, rect: DrawRect, vals: DrawVal, diff: i64, gmin: u64, gmax: u64) -> Result<()>
+// `trend` is the memory growth per hour and whether it has been growing longer than allowed by --leak-warn
+fn draw_mem_head<W>(
+    w: &mut W,
+    rect: DrawRect,
+    vals: DrawVal,
+    diff: i64,
+    (gmin, gmax): (u64, u64),
+    trend: Option<(f64, bool)>,
+) -> Result<()>
 where
     W: Write,
 {
     {
-        let gmax_val = format_mem(gmax);
+        let gmax_val = format_mem_bytes(gmax);
         let gmax_str = format!("{:>5}|", gmax_val);
         queue!(w, cursor::MoveTo(rect.x, rect.y), style::Print(gmax_str))?;
     }
     {
-        let gmin_val = format_mem(gmin);
+        let gmin_val = format_mem_bytes(gmin);
         let gmin_str = format!("{:>5}|", gmin_val);
         queue!(w, cursor::MoveTo(rect.x, rect.y + rect.h - 1), style::Print(gmin_str))?;
     }
     if vals.max != 0 {
-        let max_val = format_mem(vals.max);
+        let max_val = format_mem_bytes(vals.max);
         let max_str = format!("{:>5}|", max_val);
         queue!(w, cursor::MoveTo(rect.x, rect.y + 3), style::Print(max_str))?;
     } else {
@@ -510,11 +869,11 @@ where
     if diff == 0 {
         queue!(w, cursor::MoveTo(rect.x, rect.y + 2), style::Print("  -  \u{2502}"))?;
     } else {
-        let diff_val = format_diff(diff);
+        let diff_val = format_diff_bytes(diff);
         let diff_str = format!("{:>5}|", diff_val);
         queue!(w, cursor::MoveTo(rect.x, rect.y + 2), style::Print(diff_str))?;
     }
-    let curr_val = format_mem(vals.curr);
+    let curr_val = format_mem_bytes(vals.curr);
     let curr_str = format!("{:>5}", curr_val);
     queue!(
         w,
@@ -524,28 +883,84 @@ where
         style::ResetColor,
         style::Print("\u{2502}")
     )?;
-    for idx in 4..rect.h - 1 {
+    let mut first_empty = 4;
+    // The trend takes two lines under the maximum: the rate and its unit. It is hidden if the graph is too low
+    if let Some((rate, warned)) = trend {
+        if rect.h > 6 {
+            let color = if warned { Color::Red } else { Color::Reset };
+            let rate_str = format!("{:>5}", format_diff_bytes(rate.round() as i64));
+            queue!(
+                w,
+                cursor::MoveTo(rect.x, rect.y + 4),
+                style::SetForegroundColor(color),
+                style::Print(rate_str),
+                style::ResetColor,
+                style::Print("\u{2502}"),
+                cursor::MoveTo(rect.x, rect.y + 5),
+                style::SetForegroundColor(color),
+                style::Print("   /h"),
+                style::ResetColor,
+                style::Print("\u{2502}")
+            )?;
+            first_empty = 6;
+        }
+    }
+    for idx in first_empty..rect.h - 1 {
         queue!(w, cursor::MoveTo(rect.x, rect.y + idx), style::Print("     \u{2502}"))?;
     }
     Ok(())
 }
 
-fn draw_title<W>(w: &mut W, proc: &Process, cnt: usize, mode: TitleMode) -> Result<()>
+fn draw_title<W>(w: &mut W, proc: &Process, cnt: usize, mode: TitleMode, with_selector: bool, alert: &str) -> Result<()>
 where
     W: Write,
 {
     let y = proc.y;
-    let pid = format!("[{}]-[{}] ", cnt, proc.pid);
-    let maxw = proc.w as usize - pid.len();
+    let mut pid = match proc.tree {
+        Some(ref t) if t.members.len() > 1 => format!("[{}]-[{}+{}] ", cnt, proc.pid, t.members.len() - 1),
+        _ => format!("[{}]-[{}] ", cnt, proc.pid),
+    };
+    if proc.restarts != 0 {
+        pid = format!("{}[R{}] ", pid, proc.restarts);
+    }
+    if with_selector && !proc.selector.is_empty() && proc.selector != proc.pid.to_string() {
+        pid = format!("{}<{}> ", pid, proc.selector);
+    }
+    if !alert.is_empty() {
+        pid = format!("{}!{}! ", pid, alert);
+    }
+    // A long selector or alert text can be wider than the box: the command line is hidden then
+    let pid = cut_string(&pid, proc.w as usize);
+    let maxw = (proc.w as usize).saturating_sub(pid.width());
     let cmd = fade_str_left(&proc.description(mode), maxw);
-    let spare = maxw - cmd.width();
+    let spare = maxw.saturating_sub(cmd.width());
     let title = if spare == 0 {
         format!("{}{}", pid, cmd)
     } else {
         let left = spare / 2;
         format!("{}{}{}{}", "-".repeat(left), pid, cmd, "-".repeat(spare - left))
     };
-    queue!(w, cursor::MoveTo(0, y), style::Print(title))?;
+    if alert.is_empty() && !proc.dropping {
+        queue!(w, cursor::MoveTo(0, y), style::Print(title))?;
+    } else {
+        // The process would be removed by the edited regular expression: use the prompt colors.
+        // Otherwise, flash the title: switch colors every refresh.
+        let (fg, bg) = if proc.dropping {
+            (Color::Black, Color::Yellow)
+        } else if proc.cpu.count % 2 == 0 {
+            (Color::White, Color::Red)
+        } else {
+            (Color::Red, Color::Reset)
+        };
+        queue!(
+            w,
+            cursor::MoveTo(0, y),
+            style::SetForegroundColor(fg),
+            style::SetBackgroundColor(bg),
+            style::Print(title),
+            style::ResetColor
+        )?;
+    }
 
     let y = y + 1;
     let delta_r = if let Some(b) = proc.mark_r_io { proc.io_r_total - b } else { proc.io_r_delta };
@@ -575,7 +990,15 @@ where
     Ok(())
 }
 
-pub(crate) fn draw_counter<W>(w: &mut W, proc: &mut Process, cnt: usize, mode: TitleMode, conf: &Config) -> Result<()>
+pub(crate) fn draw_counter<W>(
+    w: &mut W,
+    proc: &mut Process,
+    cnt: usize,
+    mode: TitleMode,
+    offset: usize,
+    conf: &Config,
+    now: SystemTime,
+) -> Result<()>
 where
     W: Write,
 {
@@ -584,7 +1007,9 @@ where
         return Ok(());
     }
 
-    draw_title(w, proc, cnt, mode)?;
+    let firing: Vec<&str> =
+        conf.alerts.iter().zip(proc.alerts.iter()).filter(|(_, a)| a.firing).map(|(r, _)| r.text.as_str()).collect();
+    draw_title(w, proc, cnt, mode, conf.selector_count() > 1, &firing.join(", "))?;
 
     let mut cpu_w = proc.w;
     let mut mem_w = proc.w;
@@ -602,7 +1027,7 @@ where
         (hh, proc.h - hh, hh, 0)
     };
 
-    proc.mem.calculate_range();
+    proc.mem.calculate_range((mem_w - 6) as usize, offset, conf);
 
     if conf.graphs != Graph::Mem {
         let head_cpu_rect = DrawRect { y: proc.y + 2, h: hc - 3, ..Default::default() };
@@ -623,18 +1048,21 @@ where
         let diff = proc.mem.last_diff();
         let mem_head_rect = DrawRect { x: dx, y: proc.y + dym + yshift, w: 0, h: hm - yshift - 1 };
         let mem_head_val = DrawVal { curr: proc.mem.last(), max: proc.mem.max };
-        draw_mem_head(w, mem_head_rect, mem_head_val, diff, min_val, max_val)?;
+        let trend = proc.mem_trend().map(|t| (t, proc.leak_warned));
+        draw_mem_head(w, mem_head_rect, mem_head_val, diff, (min_val, max_val), trend)?;
     }
 
     if conf.graphs != Graph::Mem {
-        proc.cpu.update(cpu_w - 5, hc - 3, conf);
+        proc.cpu.update(cpu_w - 5, hc - 3, offset, conf);
         let cpu_rect = DrawRect { x: 5, y: proc.y + 2, w: cpu_w - 5, h: hc - 3 };
-        draw_spikes(w, &proc.cpu, cpu_rect, 5, proc.dead_since)?;
+        // `now` is the time of the latest sample, so the time since exit is correct during replay too
+        let exited = proc.dead_since.map(|d| now.duration_since(d).unwrap_or_default());
+        draw_spikes(w, &proc.cpu, cpu_rect, 5, exited, proc.exit_status.as_deref())?;
     }
     if conf.graphs != Graph::Cpu {
-        proc.mem.update(mem_w - 6, hm - yshift - 1, conf);
+        proc.mem.update(mem_w - 6, hm - yshift - 1, offset, conf);
         let mem_rect = DrawRect { x: dx + 6, y: proc.y + dym + yshift, w: mem_w - 6, h: hm - yshift - 1 };
-        draw_spikes(w, &proc.mem, mem_rect, 6, None)?;
+        draw_spikes(w, &proc.mem, mem_rect, 6, None, None)?;
     }
 
     Ok(())
@@ -643,6 +1071,262 @@ where
 #[cfg(test)]
 mod var_test {
     use super::*;
+    use sysinfo::PidExt;
+
+    #[test]
+    fn restart() {
+        let mut p = Process::new(Pid::from_u32(1), "srv".to_string(), String::new(), String::new());
+        p.add(50, 100);
+        p.add(40, 110);
+        p.dead = true;
+        p.restart(Pid::from_u32(2));
+        assert!(!p.dead);
+        assert_eq!(p.restarts, 1);
+        // the first CPU value of the restarted process is skipped
+        p.add(70, 120);
+        p.add(60, 130);
+        assert_eq!(p.cpu.values, vec![0, 40, 0, 60]);
+        assert_eq!(p.mem.values, vec![100, 110, 120, 130]);
+        assert_eq!(p.mem.restarts, vec![2]);
+
+        let cfg = Config::default();
+        p.mem.calculate_range(4, 0, &cfg);
+        p.mem.update(4, 2, 0, &cfg);
+        let spikes: String = p.mem.screen[8..12].iter().collect();
+        assert_eq!(spikes, " +R+");
+    }
+
+    #[test]
+    fn restart_io() {
+        let mut p = Process::new(Pid::from_u32(1), "srv".to_string(), String::new(), String::new());
+        let s = |total, delta| Sample {
+            io_r_total: total,
+            io_w_total: total,
+            io_r_delta: delta,
+            io_w_delta: delta,
+            ..Sample::default()
+        };
+        p.add_sample(&s(10, 10), SystemTime::UNIX_EPOCH);
+        p.add_sample(&s(15, 5), SystemTime::UNIX_EPOCH);
+        assert_eq!((p.io_r_delta, p.io_w_delta), (5, 5));
+        p.dead = true;
+        p.restart(Pid::from_u32(2));
+        // the deltas of the new process's first sample are its totals
+        p.add_sample(&s(7, 7), SystemTime::UNIX_EPOCH);
+        assert_eq!((p.io_r_total, p.io_r_delta, p.io_w_delta), (7, 0, 0));
+        assert_eq!(p.samples().last().map(|(_, pid, s)| (pid.as_u32(), s.io_r_delta)), Some((2, 0)));
+    }
+
+    #[test]
+    fn scroll_back() {
+        let mut c = Counter { display_cnt: 3, keep: 5, scale_to: 10, ..Counter::default() };
+        for v in 1..=7 {
+            c.add(v);
+        }
+        // values older than the retention are dropped
+        assert_eq!(c.kept(), &[3, 4, 5, 6, 7]);
+        // ... in batches, not one by one
+        assert_eq!(c.values.len(), 7);
+        for v in 8..=11 {
+            c.add(v);
+        }
+        assert_eq!(c.values, vec![7, 8, 9, 10, 11]);
+        assert_eq!(c.kept(), &[7, 8, 9, 10, 11]);
+        for v in 1..=7 {
+            c.add(v);
+        }
+        let cfg = Config { detail: Detail::Low, ..Config::default() };
+        let vals = |cols: Vec<(u64, bool)>| cols.iter().map(|(v, _)| *v).collect::<Vec<u64>>();
+        assert_eq!(vals(c.columns(3, 0, &cfg)), vec![5, 6, 7]);
+        assert_eq!(vals(c.columns(3, 1, &cfg)), vec![4, 5, 6]);
+        // cannot scroll beyond the oldest kept value
+        assert_eq!(vals(c.columns(3, 10, &cfg)), vec![3, 4, 5]);
+        assert_eq!(vals(c.columns(8, 2, &cfg)), vec![3, 4, 5, 6, 7]);
+
+        c.update(3, 1, 2, &cfg);
+        let spikes: String = c.screen[3..6].iter().collect();
+        assert_eq!(spikes, " ++");
+        c.add(0);
+        c.update(3, 1, 0, &cfg);
+        let spikes: String = c.screen[3..6].iter().collect();
+        assert_eq!(spikes, " +-");
+    }
+
+    #[test]
+    fn zoom() {
+        let mut c = Counter { keep: 100, ..Counter::default() };
+        for v in [1, 5, 3, 2, 8, 4, 6].iter() {
+            c.add(*v);
+        }
+        c.mark_restart();
+        c.add(9);
+        // columns are aligned to the number of added values: [1 5 3] [2 8 4] [6 9]
+        let mut cfg = Config { zoom: 3, ..Config::default() };
+        assert_eq!(c.columns(5, 0, &cfg), vec![(5, false), (8, false), (9, true)]);
+        cfg.reduce = Reduce::Min;
+        assert_eq!(c.columns(2, 0, &cfg), vec![(2, false), (6, true)]);
+        cfg.reduce = Reduce::Avg;
+        assert_eq!(c.columns(2, 3, &cfg), vec![(3, false), (4, false)]);
+
+        cfg.zoom = 5;
+        assert!(cfg.change_zoom(false));
+        assert_eq!(cfg.zoom, 2);
+        assert!(!Config::default().change_zoom(false));
+        assert!(cfg.change_zoom(true));
+        assert_eq!(cfg.zoom, 5);
+    }
+
+    #[test]
+    fn trend() {
+        let mut p = Process::new(Pid::from_u32(1), "srv".to_string(), String::new(), String::new());
+        p.mem.keep = 100;
+        p.add(0, 100);
+        p.add(0, 110);
+        assert_eq!(p.mem.slope(), None);
+        for v in [115, 130, 140].iter() {
+            p.add(0, *v);
+        }
+        assert!((p.mem.slope().unwrap() - 10.0).abs() < 1e-9);
+        // values before a restart are ignored
+        p.dead = true;
+        p.restart(Pid::from_u32(2));
+        for v in [500, 490, 480].iter() {
+            p.add(0, *v);
+        }
+        assert!((p.mem.slope().unwrap() + 10.0).abs() < 1e-9);
+
+        let t = |s| SystemTime::UNIX_EPOCH + Duration::from_secs(s);
+        // the trend uses the time between samples, not the refresh interval
+        let mut q = Process::new(Pid::from_u32(3), "srv".to_string(), String::new(), String::new());
+        q.set_keep(100);
+        for i in 0..4u64 {
+            q.add_sample(&Sample { mem: 1024 * i, ..Sample::default() }, t(2 * i));
+        }
+        assert!((q.mem_trend().unwrap() - 1024.0 * 1800.0).abs() < 1e-6);
+        let window = Duration::from_secs(2);
+        assert!(!p.check_leak(window, t(1)));
+        assert!(p.growing_since.is_none());
+        for v in [490, 500, 510].iter() {
+            p.add(0, *v);
+        }
+        assert!(!p.check_leak(window, t(2)));
+        assert!(!p.check_leak(window, t(3)));
+        assert!(p.check_leak(window, t(4)));
+        // warns once while memory keeps growing
+        assert!(!p.check_leak(window, t(5)));
+        assert!(p.leak_warned);
+    }
+
+    #[test]
+    fn mark_stats() {
+        let mut p = Process::new(Pid::from_u32(1), "srv".to_string(), String::new(), String::new());
+        p.cpu.display_cnt = 3;
+        p.mem.display_cnt = 3;
+        for v in 1..=5 {
+            p.add(v, v * 10);
+        }
+        // the whole session counts, not only the kept values, but the first CPU value is skipped
+        let (cpu, mem) = p.stats();
+        let (cpu, mem) = (cpu.unwrap(), mem.unwrap());
+        assert_eq!((cpu.min, cpu.avg, cpu.p50), (2, 3.5, 3));
+        assert_eq!((mem.min, mem.avg, mem.p50), (10, 30.0, 30));
+        p.toggle_mark();
+        assert_eq!(p.stats().0, None);
+        p.add(6, 60);
+        p.add(7, 70);
+        let (cpu, mem) = p.stats();
+        assert_eq!((cpu.unwrap().min, mem.unwrap().max), (6, 70));
+        p.toggle_mark();
+        assert_eq!(p.stats().0.unwrap().min, 2);
+    }
+
+    #[test]
+    fn budget() {
+        let mut p = Process::new(Pid::from_u32(1), "srv".to_string(), String::new(), String::new());
+        let rules = vec![Rule::parse("cpu>50").unwrap(), Rule::parse("mem>1K").unwrap()];
+        p.add(40, 1024);
+        assert!(p.over_budget(&rules).is_empty());
+        p.add(70, 512);
+        p.add(10, 512);
+        // the peak value counts, not the last one
+        let over: Vec<&str> = p.over_budget(&rules).iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(over, vec!["cpu>50"]);
+        // resetting the graph maximums does not hide the peak
+        p.reset_max();
+        assert_eq!(p.peak(), (70, 1024));
+        assert_eq!(p.over_budget(&rules).len(), 1);
+    }
+
+    #[test]
+    fn mem_head_trend() {
+        let draw = |h: u16, trend: Option<(f64, bool)>| {
+            let mut out: Vec<u8> = Vec::new();
+            let rect = DrawRect { h, ..Default::default() };
+            let vals = DrawVal { curr: 2048, max: 4096 };
+            draw_mem_head(&mut out, rect, vals, 0, (1024, 8192), trend).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let rate = 12.0 * 1024.0 * 1024.0;
+        let out = draw(8, Some((rate, false)));
+        assert!(out.contains(" +12M"));
+        assert!(out.contains("   /h"));
+        assert!(!out.contains(&format!("{}", style::SetForegroundColor(Color::Red))));
+        let out = draw(8, Some((rate, true)));
+        assert!(out.contains(&format!("{} +12M", style::SetForegroundColor(Color::Red))));
+        // no room for the trend
+        assert!(!draw(6, Some((rate, false))).contains("/h"));
+        assert!(!draw(8, None).contains("/h"));
+    }
+
+    #[test]
+    fn narrow_title() {
+        let mut p = Process::new(Pid::from_u32(1234), "srv --port 80".to_string(), String::new(), String::new());
+        p.w = 20;
+        p.selector = "pidfile /very/long/path/to/the/daemon.pid".to_string();
+        let mut out: Vec<u8> = Vec::new();
+        draw_title(&mut out, &p, 1, TitleMode::Cmd, true, "").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // the prefix is cut to the box width, and there is no room for the command line
+        assert!(out.contains("[1]-[1234] <pidfile \u{1b}"));
+        assert!(!out.contains("srv"));
+
+        // several alerts firing at once on a narrow box
+        p.w = 12;
+        let mut out: Vec<u8> = Vec::new();
+        draw_title(&mut out, &p, 10, TitleMode::Cmd, false, "cpu>90%:30s, mem>2G, mem>1G").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("[10]-[1234] \u{1b}"));
+    }
+
+    #[test]
+    fn tree_tick() {
+        let p = Pid::from_u32;
+        let s = |cpu, mem, io| Sample { cpu, mem, io_r_total: io, io_w_total: io, io_r_delta: 1, io_w_delta: 1 };
+        let mut t = Tree::default();
+
+        let fresh: HashMap<Pid, Sample> = [(p(1), s(10, 100, 5)), (p(2), s(20, 200, 7))].iter().copied().collect();
+        let total = t.tick(fresh);
+        // CPU of new members is skipped
+        assert_eq!((total.cpu, total.mem, total.io_r_total, total.io_r_delta), (0, 300, 12, 2));
+
+        let fresh: HashMap<Pid, Sample> = [(p(1), s(10, 100, 5)), (p(2), s(20, 200, 8))].iter().copied().collect();
+        let total = t.tick(fresh);
+        assert_eq!((total.cpu, total.mem, total.io_r_total, total.io_r_delta), (30, 300, 13, 2));
+
+        // Member 2 exits: it is counted for one more tick, and its IO is kept forever
+        let fresh: HashMap<Pid, Sample> = [(p(1), s(10, 100, 5)), (p(3), s(5, 50, 1))].iter().copied().collect();
+        let total = t.tick(fresh);
+        assert_eq!((total.cpu, total.mem, total.io_r_total, total.io_w_total), (30, 350, 14, 14));
+        assert_eq!(t.members.len(), 2);
+
+        let fresh: HashMap<Pid, Sample> = [(p(1), s(10, 100, 5)), (p(3), s(5, 50, 1))].iter().copied().collect();
+        let total = t.tick(fresh);
+        assert_eq!((total.cpu, total.mem, total.io_r_total), (15, 150, 14));
+
+        let total = t.tick(HashMap::new());
+        assert_eq!((total.cpu, total.mem, total.io_r_total), (15, 150, 14));
+        assert!(t.members.is_empty());
+    }
 
     #[test]
     fn idx_low() {
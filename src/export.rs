@@ -0,0 +1,214 @@
+/*
+ * Export of the collected history to CSV or JSON. Every row is a single sample of a process:
+ *   timestamp, pid, title, CPU%, memory in bytes, total I/O read and write in bytes
+ * Timestamp is local time in RFC 3339 format with milliseconds.
+ */
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+
+use crate::config::TitleMode;
+use crate::counter::Process;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    // Selects the format by file extension: `.json` means JSON, anything else is CSV
+    pub(crate) fn from_path(path: &str) -> Format {
+        match Path::new(path).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+
+    fn ext(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+        }
+    }
+}
+
+// Returns the name of the file to export to by a hotkey: `export-` followed by the current time
+pub(crate) fn file_name(fmt: Format) -> String {
+    let time_now = Local::now();
+    time_now.format(&format!("export-%Y%m%d-%H%M%S.{}", fmt.ext())).to_string()
+}
+
+// Writes the history of all processes to a file
+pub(crate) fn export(path: &str, fmt: Format, procs: &[Process], mode: TitleMode) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    match fmt {
+        Format::Csv => write_csv(&mut out, procs, mode)?,
+        Format::Json => write_json(&mut out, procs, mode)?,
+    }
+    out.flush()
+}
+
+// Returns local time in RFC 3339 format with milliseconds
+pub(crate) fn format_time(tm: SystemTime) -> String {
+    DateTime::<Local>::from(tm).to_rfc3339_opts(SecondsFormat::Millis, false)
+}
+
+// Quotes a CSV field if it contains separators, quotes, or line breaks
+fn csv_str(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Returns a quoted JSON string
+pub(crate) fn json_str(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn write_csv<W: Write>(w: &mut W, procs: &[Process], mode: TitleMode) -> io::Result<()> {
+    writeln!(w, "timestamp,pid,title,cpu,mem,io_read,io_write")?;
+    for p in procs.iter() {
+        let title = csv_str(&p.description(mode));
+        for (tm, pid, s) in p.samples() {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{}",
+                format_time(tm),
+                pid,
+                title,
+                s.cpu,
+                s.mem,
+                s.io_r_total * 1024,
+                s.io_w_total * 1024
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_json<W: Write>(w: &mut W, procs: &[Process], mode: TitleMode) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for p in procs.iter() {
+        let title = json_str(&p.description(mode));
+        for (tm, pid, s) in p.samples() {
+            if !first {
+                write!(w, ",")?;
+            }
+            first = false;
+            write!(
+                w,
+                "\n  {{\"timestamp\":{},\"pid\":{},\"title\":{},\"cpu\":{},\"mem\":{},\"io_read\":{},\"io_write\":{}}}",
+                json_str(&format_time(tm)),
+                pid,
+                title,
+                s.cpu,
+                s.mem,
+                s.io_r_total * 1024,
+                s.io_w_total * 1024
+            )?;
+        }
+    }
+    writeln!(w, "\n]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter::Sample;
+    use std::time::{Duration, UNIX_EPOCH};
+    use sysinfo::{Pid, PidExt};
+
+    fn procs() -> Vec<Process> {
+        let mut p = Process::new(Pid::from_u32(42), "sh -c \"a,b\"".to_string(), String::new(), "sh".to_string());
+        p.cpu.display_cnt = 2;
+        p.mem.display_cnt = 2;
+        for idx in 0..3u64 {
+            let s =
+                Sample { cpu: 10 + idx, mem: 1000 + idx, io_r_total: idx, io_w_total: 2 * idx, ..Sample::default() };
+            p.add_sample(&s, UNIX_EPOCH + Duration::from_secs(idx));
+        }
+        vec![p]
+    }
+
+    fn lines(fmt: Format) -> Vec<String> {
+        let mut out: Vec<u8> = Vec::new();
+        match fmt {
+            Format::Csv => write_csv(&mut out, &procs(), TitleMode::Cmd).unwrap(),
+            Format::Json => write_json(&mut out, &procs(), TitleMode::Cmd).unwrap(),
+        }
+        String::from_utf8(out).unwrap().lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(Format::from_path("out.json"), Format::Json);
+        assert_eq!(Format::from_path("out.JSON"), Format::Json);
+        assert_eq!(Format::from_path("out.csv"), Format::Csv);
+        assert_eq!(Format::from_path("out"), Format::Csv);
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(csv_str("plain"), "plain");
+        assert_eq!(csv_str("a,\"b\""), "\"a,\"\"b\"\"\"");
+        assert_eq!(json_str("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn csv() {
+        let l = lines(Format::Csv);
+        // only the last samples that fit the graph are kept
+        assert_eq!(l.len(), 3);
+        assert_eq!(l[0], "timestamp,pid,title,cpu,mem,io_read,io_write");
+        assert!(l[1].ends_with(",42,\"sh -c \"\"a,b\"\"\",11,1001,1024,2048"));
+        // timestamps are local time, so check only they are in order
+        assert!(l[1] < l[2]);
+    }
+
+    #[test]
+    fn stitched() {
+        let mut p = Process::new(Pid::from_u32(42), "srv".to_string(), String::new(), String::new());
+        p.add_sample(&Sample::default(), UNIX_EPOCH);
+        p.restart(Pid::from_u32(43));
+        p.add_sample(&Sample::default(), UNIX_EPOCH + Duration::from_secs(1));
+        let mut out: Vec<u8> = Vec::new();
+        write_csv(&mut out, &[p], TitleMode::Cmd).unwrap();
+        let pids: Vec<String> =
+            String::from_utf8(out).unwrap().lines().skip(1).map(|l| l.split(',').nth(1).unwrap().to_string()).collect();
+        // every row keeps the PID of the process that the sample was taken from
+        assert_eq!(pids, vec!["42", "43"]);
+    }
+
+    #[test]
+    fn json() {
+        let l = lines(Format::Json);
+        assert_eq!(l.len(), 4);
+        assert_eq!(l[0], "[");
+        assert!(l[1].ends_with(
+            ",\"pid\":42,\"title\":\"sh -c \\\"a,b\\\"\",\"cpu\":11,\"mem\":1001,\"io_read\":1024,\"io_write\":2048},"
+        ));
+        assert!(!l[2].ends_with(','));
+        assert_eq!(l[3], "]");
+    }
+}
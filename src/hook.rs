@@ -0,0 +1,93 @@
+/*
+ * Hook commands(options --on-exit and --on-alert). A shell command is started in background when a
+ * watched process exits or an alert fires. The process is described in environment variables:
+ *   PWATCH_PID, PWATCH_TITLE, PWATCH_CPU, PWATCH_MEM - the PID, title, and the latest CPU% and MEM(bytes)
+ *   PWATCH_DEAD_SINCE, PWATCH_EXIT_STATUS           - when the process exited and how(exit hook only)
+ *   PWATCH_ALERT                                    - the rule that has fired(alert hook only)
+ * Hooks do not run during replay.
+ */
+use std::io;
+use std::process::{Child, Command, Stdio};
+
+use crate::config::TitleMode;
+use crate::counter::Process;
+use crate::export::format_time;
+
+// Hook commands started by pwatch that are still running. They must be waited for to avoid zombies.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    running: Vec<Child>,
+}
+
+// Returns environment variables that describe a process to a hook command
+pub(crate) fn process_vars(p: &Process, mode: TitleMode) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        ("PWATCH_PID", p.pid.to_string()),
+        ("PWATCH_TITLE", p.description(mode)),
+        ("PWATCH_CPU", p.cpu.last().to_string()),
+        ("PWATCH_MEM", p.mem.last().to_string()),
+    ];
+    if let Some(tm) = p.dead_since {
+        vars.push(("PWATCH_DEAD_SINCE", format_time(tm)));
+    }
+    if let Some(ref st) = p.exit_status {
+        vars.push(("PWATCH_EXIT_STATUS", st.clone()));
+    }
+    vars
+}
+
+impl Hooks {
+    // Starts a shell command in background: with `sh -c` on Unix, and with `cmd /C` on Windows.
+    // The command output is discarded to keep the screen intact.
+    pub(crate) fn run(&mut self, cmd: &str, vars: &[(&'static str, String)]) -> io::Result<()> {
+        #[cfg(windows)]
+        let mut command = {
+            let mut c = Command::new("cmd");
+            c.arg("/C").arg(cmd);
+            c
+        };
+        #[cfg(not(windows))]
+        let mut command = {
+            let mut c = Command::new("sh");
+            c.arg("-c").arg(cmd);
+            c
+        };
+        command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+        for (name, val) in vars.iter() {
+            command.env(name, val);
+        }
+        self.running.push(command.spawn()?);
+        Ok(())
+    }
+
+    // Forgets hook commands that have finished
+    pub(crate) fn reap(&mut self) {
+        self.running.retain_mut(|c| !matches!(c.try_wait(), Ok(Some(_)) | Err(_)));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn run_hook() {
+        let path = std::env::temp_dir().join(format!("pwatch-test-hook-{}", std::process::id()));
+        let cmd = format!("echo \"$PWATCH_PID $PWATCH_ALERT\" > {}", path.to_string_lossy());
+        let mut hooks = Hooks::default();
+        hooks.run(&cmd, &[("PWATCH_PID", "42".to_string()), ("PWATCH_ALERT", "mem>1G".to_string())]).unwrap();
+        for _ in 0..100 {
+            hooks.reap();
+            if hooks.running.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(hooks.running.is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "42 mem>1G\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,16 +1,33 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::Write;
-use std::time::SystemTime;
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::config::{Config, Pack};
-use crate::counter::{draw_counter, Process};
-use crate::ux::{cut_string, format_duration};
+use crate::config::{Config, MatchOn, Pack};
+use crate::control::{Command, Control};
+use crate::counter::{draw_counter, Process, Sample, Tree};
+use crate::export::{self, Format};
+use crate::hook::{self, Hooks};
+use crate::metrics::Exporter;
+use crate::record::{from_millis, Entry, Recorder, Replay};
+use crate::shot::ScreenShot;
+use crate::statsd::StatsD;
+use crate::summary;
+use crate::ux::{cut_string, fade_str_left, format_duration, format_exit_status, format_mem_bytes};
 
 use crossterm::{cursor, queue, style, style::Color, terminal, Result};
 use regex::Regex;
-use sysinfo::{CpuExt, ProcessExt, System, SystemExt};
+use sysinfo::{CpuExt, Pid, PidExt, ProcessExt, Signal, System, SystemExt, UserExt};
 use unicode_width::UnicodeWidthStr;
 
-// use log::*;
+use log::*;
+
+// How long a message about a finished action stays in the top line
+const STATUS_TIME: Duration = Duration::from_secs(5);
+// How long the process started by pwatch has to exit after SIGTERM before it is killed
+const STOP_GRACE: Duration = Duration::from_secs(3);
 
 pub(crate) struct Layout {
     pub(crate) w: u16,
@@ -22,7 +39,20 @@ pub(crate) struct Layout {
     pub(crate) mem_usage: u64,  // total MEM%
     pub(crate) top_item: usize, // first shown counter (used only if there are hidden counters)
     pub(crate) mark_since: Option<SystemTime>,
-    show_help: bool, // show help bar(true) or total CPU/MEM(false) in the top line
+    pub(crate) offset: usize, // number of samples the graphs are scrolled back in time by
+    show_help: bool,          // show help bar(true) or total CPU/MEM(false) in the top line
+    show_stats: bool,         // show statistics table(true) or graphs(false)
+    child: Option<Child>,     // process started by pwatch (spawn mode)
+    child_status: Option<ExitStatus>,
+    recorder: Option<Recorder>, // writes all samples to a file (option --record)
+    replay: Option<Replay>,     // recorded session that replaces live data (option --replay)
+    bell: bool,                 // an alert has fired since the last check
+    hooks: Hooks,
+    exporter: Option<Exporter>,        // serves Prometheus metrics (option --listen)
+    statsd: Option<StatsD>,            // sends metrics to StatsD (option --statsd)
+    control: Option<Control>,          // receives commands from scripts (option --control)
+    pub(crate) prompt: Option<String>, // regular expression being edited in the prompt
+    status: Option<(String, Instant)>, // result of the latest hotkey action, shown in the top line
 }
 
 pub(crate) enum Scroll {
@@ -45,8 +75,274 @@ impl Layout {
             mem_usage: 0,
             top_item: 0,
             mark_since: None,
+            offset: 0,
             show_help: false,
+            show_stats: false,
+            child: None,
+            child_status: None,
+            recorder: None,
+            replay: None,
+            bell: false,
+            hooks: Hooks::default(),
+            exporter: None,
+            statsd: None,
+            control: None,
+            prompt: None,
+            status: None,
+        }
+    }
+
+    // Watch the process started by pwatch. Its PID must be already in the config PID list.
+    pub(crate) fn set_child(&mut self, child: Child) {
+        self.child = Some(child);
+    }
+
+    // Checks whether the process started by pwatch has exited. The process must be reaped before
+    // refreshing the process list, otherwise it stays in the list as a zombie.
+    fn reap_child(&mut self) {
+        if self.child_status.is_some() {
+            return;
+        }
+        let child = match self.child.as_mut() {
+            None => return,
+            Some(c) => c,
+        };
+        if let Ok(Some(st)) = child.try_wait() {
+            self.child_status = Some(st);
+            let pid = Pid::from_u32(child.id());
+            for ap in self.procs.iter_mut() {
+                if ap.pid == pid && !ap.dead {
+                    ap.exit_status = Some(format_exit_status(st));
+                    // A process tree is marked dead only after all its members have exited
+                    if ap.tree.is_none() {
+                        ap.dead = true;
+                        ap.dead_since = Some(SystemTime::now());
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn set_recorder(&mut self, rec: Recorder) {
+        self.recorder = Some(rec);
+    }
+
+    // Returns the error that has stopped recording
+    pub(crate) fn record_error(&self) -> Option<&std::io::Error> {
+        self.recorder.as_ref().and_then(|r| r.error.as_ref())
+    }
+
+    pub(crate) fn set_exporter(&mut self, exp: Exporter) {
+        self.exporter = Some(exp);
+    }
+
+    pub(crate) fn set_statsd(&mut self, sd: StatsD) {
+        self.statsd = Some(sd);
+    }
+
+    pub(crate) fn set_control(&mut self, ctl: Control) {
+        self.control = Some(ctl);
+    }
+
+    // Executes commands received from the control socket. Returns true if the screen must be redrawn.
+    pub(crate) fn run_commands(&mut self) -> bool {
+        let reqs = match self.control.as_ref() {
+            None => return false,
+            Some(ctl) => ctl.pending(),
+        };
+        let mut redraw = false;
+        for req in reqs {
+            let res = self.run_command(&req.cmd);
+            match res {
+                Ok(_) => info!("Control command {:?} done", req.cmd),
+                Err(ref e) => warn!("Control command {:?} failed: {}", req.cmd, e),
+            }
+            redraw |= res.is_ok();
+            req.reply(res);
         }
+        redraw
+    }
+
+    fn run_command(&mut self, cmd: &Command) -> std::result::Result<String, String> {
+        match cmd {
+            Command::Watch(_) | Command::Unwatch(_) if self.replay.is_some() => {
+                Err("the watch list cannot be changed during replay".to_string())
+            }
+            Command::Watch(pid) => {
+                // the process may have started after the latest refresh
+                if !self.system.refresh_process(*pid) {
+                    return Err(format!("process {} not found", pid));
+                }
+                self.config.unwatched.retain(|pd| pd != pid);
+                if !self.config.pid_list.contains(pid) {
+                    self.config.pid_list.push(*pid);
+                }
+                Ok(String::new())
+            }
+            Command::Unwatch(pid) => {
+                let cnt = self.procs.len();
+                self.procs.retain(|ap| ap.pid != *pid);
+                self.config.pid_list.retain(|pd| pd != pid);
+                if cnt == self.procs.len() {
+                    return Err(format!("process {} is not watched", pid));
+                }
+                if !self.config.unwatched.contains(pid) {
+                    self.config.unwatched.push(*pid);
+                }
+                self.top_item = self.top_item.min(self.procs.len().saturating_sub(1));
+                Ok(String::new())
+            }
+            Command::Mark => {
+                self.toggle_mark();
+                Ok(String::new())
+            }
+            Command::Screenshot => {
+                let mut shot = ScreenShot::new(self.w, self.h);
+                self.place();
+                self.draw_counters(&mut shot).and_then(|_| shot.flush()).map_err(|e| e.to_string())?;
+                Ok(String::new())
+            }
+            Command::Export(path) => {
+                let path = path.clone().unwrap_or_else(|| export::file_name(Format::Csv));
+                self.export(&path, Format::from_path(&path)).map_err(|e| e.to_string())?;
+                Ok(path)
+            }
+        }
+    }
+
+    pub(crate) fn set_replay(&mut self, replay: Replay) {
+        self.replay = Some(replay);
+    }
+
+    pub(crate) fn is_replay(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    // Returns true if the whole recorded session has been replayed
+    pub(crate) fn replay_finished(&self) -> bool {
+        self.replay.as_ref().map(|r| r.finished()).unwrap_or(false)
+    }
+
+    // Returns the time of the latest refresh: the current time for live data, and the recorded
+    // time during replay
+    pub(crate) fn sample_time(&self) -> SystemTime {
+        self.replay.as_ref().and_then(|r| r.time()).unwrap_or_else(SystemTime::now)
+    }
+
+    // Returns the number of samples kept for the history time. During replay, samples come at the
+    // recorded interval that can differ from the refresh interval.
+    fn history_len(&self) -> usize {
+        let interval = self.replay.as_ref().and_then(|r| r.average_interval()).unwrap_or(self.config.freq);
+        self.config.history_len(interval)
+    }
+
+    // Returns the time between samples in milliseconds: the refresh interval for live data, and the
+    // recorded interval during replay
+    pub(crate) fn sample_interval(&self) -> u64 {
+        self.replay.as_ref().and_then(|r| r.recorded_interval()).unwrap_or(self.config.freq)
+    }
+
+    // Pauses or resumes the replay. Returns false if no session is replayed.
+    pub(crate) fn toggle_pause(&mut self) -> bool {
+        match self.replay.as_mut() {
+            None => false,
+            Some(r) => {
+                r.paused = !r.paused;
+                true
+            }
+        }
+    }
+
+    pub(crate) fn paused(&self) -> bool {
+        self.replay.as_ref().map(|r| r.paused).unwrap_or(false)
+    }
+
+    // Changes the replay speed. Returns true if the speed has changed.
+    pub(crate) fn change_speed(&mut self, faster: bool) -> bool {
+        match self.replay.as_mut() {
+            None => false,
+            Some(r) => r.change_speed(faster),
+        }
+    }
+
+    // Returns the time to wait before the next refresh
+    pub(crate) fn update_interval(&self) -> Duration {
+        match self.replay {
+            None => Duration::from_millis(self.config.freq),
+            Some(ref r) => r.interval(self.config.freq),
+        }
+    }
+
+    // Feeds the next recorded refresh to the watched processes
+    fn update_replay(&mut self) {
+        let replay = match self.replay.as_mut() {
+            None => return,
+            Some(r) => r,
+        };
+        let tick = match replay.next_tick() {
+            None => return,
+            Some(t) => t,
+        };
+        self.cpu_usage = tick.cpu_usage;
+        self.mem_usage = tick.mem_usage;
+        let tm = from_millis(tick.ts);
+        for entry in tick.entries.iter() {
+            match entry {
+                Entry::Proc { pid, cmd, exe, title } => {
+                    if self.config.stitch && revive(&mut self.procs, cmd, *pid, false).is_some() {
+                        continue;
+                    }
+                    let mut ap = Process::new(*pid, cmd.clone(), exe.clone(), title.clone());
+                    ap.selector = "replay".to_string();
+                    self.procs.push(ap);
+                }
+                Entry::Sample { pid, sample } => {
+                    if let Some(ap) = self.procs.iter_mut().find(|ap| !ap.dead && ap.pid == *pid) {
+                        ap.add_sample(sample, tm);
+                    }
+                }
+                Entry::Dead { pid, ts, status } => {
+                    if let Some(ap) = self.procs.iter_mut().find(|ap| !ap.dead && ap.pid == *pid) {
+                        ap.dead = true;
+                        ap.dead_since = Some(from_millis(*ts));
+                        if !status.is_empty() {
+                            ap.exit_status = Some(status.clone());
+                        }
+                    }
+                }
+            }
+        }
+        self.procs.sort();
+    }
+
+    // Returns true if pwatch has started a process and the process has exited
+    pub(crate) fn child_finished(&self) -> bool {
+        self.child_status.is_some()
+    }
+
+    // Stops the process started by pwatch if it is still running: asks it to terminate, and kills it
+    // if it does not exit in time. Returns how the process finished if it has exited by itself, and
+    // None if pwatch has stopped it.
+    pub(crate) fn finish_child(&mut self) -> Option<ExitStatus> {
+        if self.child_status.is_some() {
+            return self.child_status;
+        }
+        let child = self.child.as_mut()?;
+        let pid = Pid::from_u32(child.id());
+        let asked = self.system.refresh_process(pid)
+            && self.system.process(pid).and_then(|p| p.kill_with(Signal::Term)).unwrap_or(false);
+        if asked {
+            let start = Instant::now();
+            while start.elapsed() < STOP_GRACE {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return None;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        None
     }
 
     // Terminal resize event handler
@@ -59,11 +355,182 @@ impl Layout {
         self.place();
     }
 
+    // Returns PIDs of running processes selected by a user by PIDs, pidfiles, cgroups, and regular
+    // expressions. Every PID is paired with the selector that has brought the process in.
+    fn select_procs(&self) -> Vec<(Pid, String)> {
+        let procs = self.system.processes();
+        let mut listed: Vec<(Pid, String)> = self.config.pid_list.iter().map(|pd| (*pd, pd.to_string())).collect();
+        // Pidfiles are re-read every time to pick up a restarted daemon
+        for path in self.config.pidfiles.iter() {
+            if let Some(pd) = read_pidfile(path) {
+                listed.push((pd, format!("pidfile {}", path)));
+            }
+        }
+        for path in self.config.cgroups.iter() {
+            for pd in read_cgroup(path) {
+                listed.push((pd, format!("cgroup {}", path)));
+            }
+        }
+        let mut selected: Vec<(Pid, String)> = Vec::new();
+        for (pd, sel) in listed.into_iter() {
+            if selected.iter().any(|(sp, _)| *sp == pd) {
+                continue;
+            }
+            if let Some(p) = procs.get(&pd) {
+                if self.owned_by_user(p) {
+                    selected.push((pd, sel));
+                }
+            }
+        }
+        for (pd, sel) in self.match_filters(&self.config.filters) {
+            if !selected.iter().any(|(sp, _)| *sp == pd) {
+                selected.push((pd, sel));
+            }
+        }
+        selected
+    }
+
+    // Returns PIDs of running processes that match any of regular expressions, paired with the
+    // expression. Excluded processes and processes of other users are skipped.
+    fn match_filters(&self, filters: &[String]) -> Vec<(Pid, String)> {
+        if filters.is_empty() {
+            return Vec::new();
+        }
+        let includes: Vec<Matcher> = filters.iter().map(|f| Matcher::new(f, self.config.match_on)).collect();
+        let exclude = self.excludes();
+        // pwatch command line contains the patterns, so it always matches itself
+        let me = Pid::from_u32(std::process::id());
+        let mut matched = Vec::new();
+        for p in self.system.processes().values() {
+            if p.pid() == me || exclude.iter().any(|m| m.is_match(p)) || !self.owned_by_user(p) {
+                continue;
+            }
+            if let Some(idx) = includes.iter().position(|m| m.is_match(p)) {
+                matched.push((p.pid(), filters[idx].clone()));
+            }
+        }
+        matched
+    }
+
+    // Opens the prompt to edit the regular expression. Multiple expressions are joined with `|`.
+    pub(crate) fn open_prompt(&mut self) {
+        self.prompt = Some(self.config.filters.join("|"));
+    }
+
+    // Converts the text of the prompt to the list of regular expressions. The unchanged text keeps
+    // the current expressions: joined into one, they would be a different selector, and an invalid
+    // expression would be matched as a plain substring of the whole text.
+    fn prompt_filters(&self, text: &str) -> Vec<String> {
+        if text == self.config.filters.join("|") {
+            self.config.filters.clone()
+        } else if text.trim().is_empty() {
+            Vec::new()
+        } else {
+            vec![text.to_string()]
+        }
+    }
+
+    pub(crate) fn close_prompt(&mut self) {
+        self.prompt = None;
+    }
+
+    pub(crate) fn edit_prompt(&mut self, text: String) {
+        if self.prompt.is_some() {
+            self.prompt = Some(text);
+        }
+    }
+
+    // Returns processes that the new expressions would remove: the alive ones brought in by the
+    // current expressions that do not match the new ones. With option --children, their
+    // descendants are removed too, unless they descend from a process that stays.
+    fn dropped_by(&self, matched: &[(Pid, String)]) -> HashSet<Pid> {
+        let is_matched = |pid: Pid| matched.iter().any(|(pd, _)| *pd == pid);
+        let mut dropped: HashSet<Pid> = self
+            .procs
+            .iter()
+            .filter(|ap| !ap.dead && self.config.filters.contains(&ap.selector) && !is_matched(ap.pid))
+            .map(|ap| ap.pid)
+            .collect();
+        if !self.config.children || dropped.is_empty() {
+            return dropped;
+        }
+        let tree: Vec<(Pid, Option<Pid>)> = self.system.processes().values().map(|p| (p.pid(), p.parent())).collect();
+        let kids: HashSet<Pid> = find_descendants(&tree, &dropped).into_iter().collect();
+        // Every alive watched process is a root to look for children at refresh, so a child of a
+        // process that stays would be brought back
+        let rest: HashSet<Pid> = self
+            .procs
+            .iter()
+            .filter(|ap| !ap.dead && !dropped.contains(&ap.pid) && !kids.contains(&ap.pid))
+            .map(|ap| ap.pid)
+            .chain(matched.iter().map(|(pd, _)| *pd))
+            .collect();
+        let safe: HashSet<Pid> = find_descendants(&tree, &rest).into_iter().collect();
+        let watched = |pid: &Pid| self.procs.iter().any(|ap| !ap.dead && ap.pid == *pid);
+        dropped.extend(kids.into_iter().filter(|pd| watched(pd) && !safe.contains(pd) && !rest.contains(pd)));
+        dropped
+    }
+
+    // Returns the number of processes matching the expression in the prompt, and the processes
+    // that would be removed if it were applied
+    fn prompt_preview(&self) -> (usize, HashSet<Pid>) {
+        match self.prompt {
+            None => (0, HashSet::new()),
+            Some(ref text) => {
+                let matched = self.match_filters(&self.prompt_filters(text));
+                (matched.len(), self.dropped_by(&matched))
+            }
+        }
+    }
+
+    // Replaces regular expressions with the one from the prompt. Processes brought in by the old
+    // expressions that do not match the new one are removed(see `dropped_by`), the others keep
+    // their history. New matching processes are added at the next refresh.
+    pub(crate) fn apply_prompt(&mut self) {
+        let text = match self.prompt.take() {
+            None => return,
+            Some(t) => t,
+        };
+        let filters = self.prompt_filters(&text);
+        if filters == self.config.filters {
+            return;
+        }
+        let matched = self.match_filters(&filters);
+        let dropped = self.dropped_by(&matched);
+        let old = std::mem::replace(&mut self.config.filters, filters);
+        self.procs.retain(|ap| ap.dead || !dropped.contains(&ap.pid));
+        for ap in self.procs.iter_mut().filter(|ap| !ap.dead && old.contains(&ap.selector)) {
+            if let Some((_, sel)) = matched.iter().find(|(pd, _)| *pd == ap.pid) {
+                ap.selector = sel.clone();
+            }
+        }
+        self.top_item = self.top_item.min(self.procs.len().saturating_sub(1));
+        info!("Regular expression changed to '{}'", self.config.filters.join("|"));
+    }
+
+    fn excludes(&self) -> Vec<Matcher> {
+        self.config.exclude.iter().map(|e| Matcher::new(e, self.config.match_on)).collect()
+    }
+
+    // Checks whether the process is owned by the user set in command-line: either by name or by UID
+    fn owned_by_user<P>(&self, p: &P) -> bool
+    where
+        P: ProcessExt,
+    {
+        owned_by(&self.system, self.config.user.as_deref(), p)
+    }
+
     // Refresh process list, update CPU/MEM, mark dead ones, and add new ones
-    fn update_procs(&mut self) {
+    fn update_procs(&mut self, tm: SystemTime) {
+        // The PID of an unwatched process that has exited can be reused by a new one
+        let procs = self.system.processes();
+        self.config.unwatched.retain(|pd| procs.contains_key(pd));
+        let mut selected = self.select_procs();
+        selected.retain(|(pd, _)| !self.config.unwatched.contains(pd));
         let procs = self.system.processes();
         for ap in self.procs.iter_mut() {
-            if ap.dead {
+            // A process tree is alive while any of its members is alive
+            if ap.dead || ap.tree.is_some() {
                 continue;
             }
             if procs.get(&ap.pid).is_none() {
@@ -71,41 +538,53 @@ impl Layout {
                 ap.dead_since = Some(SystemTime::now());
             }
         }
+        // Descendants are filtered the same way as the processes selected by regular expressions
+        let exclude = self.excludes();
+        let claimed = if self.config.tree {
+            let keep = accepts(&self.system, self.config.user.as_deref(), &exclude);
+            update_trees(&mut self.procs, procs, &keep, tm)
+        } else {
+            HashSet::new()
+        };
+        // Stitching revives dead processes, so their exits must be reported before it
+        self.handle_exits();
 
-        if !self.config.filter.is_empty() {
-            let flt = format!("(?i){}", self.config.filter);
-            let rx = Regex::new(&flt);
-            let low_flt = self.config.filter.to_lowercase();
-            for p in procs.values() {
-                let pname = p.exe().to_string_lossy();
-                let full_name = format!("{} {}", pname, p.name());
-                let low_name = full_name.to_lowercase();
-                let include = if let Ok(ref rr) = rx { rr.is_match(&low_name) } else { low_name.contains(&low_flt) };
-                if !include {
-                    continue;
-                }
-
-                update_proc(&mut self.procs, p);
-            }
+        let procs = self.system.processes();
+        if self.config.tree {
+            let keep = accepts(&self.system, self.config.user.as_deref(), &exclude);
+            start_trees(&mut self.procs, procs, &selected, &claimed, &keep, self.config.stitch, tm);
             self.procs.sort();
             return;
         }
 
-        for pd in &self.config.pid_list {
-            match procs.get(pd) {
-                None => {
-                    for ap in self.procs.iter_mut() {
-                        if ap.dead {
-                            continue;
-                        }
-                        if ap.pid == *pd {
-                            ap.dead = true;
-                            ap.dead_since = Some(SystemTime::now());
-                        }
-                    }
+        for (pd, sel) in selected.iter() {
+            if let Some(p) = procs.get(pd) {
+                update_proc(&mut self.procs, p, sel, self.config.stitch, tm);
+            }
+        }
+
+        if self.config.children {
+            // Keep following the children found earlier even if their parent has exited and they
+            // have been reparented
+            let seen: HashSet<Pid> = selected.iter().map(|(pd, _)| *pd).collect();
+            let mut roots = seen.clone();
+            roots.extend(self.procs.iter().filter(|ap| !ap.dead).map(|ap| ap.pid));
+            let tree: Vec<(Pid, Option<Pid>)> = procs.values().map(|p| (p.pid(), p.parent())).collect();
+            let kids = find_descendants(&tree, &roots);
+            let keep = accepts(&self.system, self.config.user.as_deref(), &exclude);
+            for pd in roots.iter().chain(kids.iter()) {
+                if seen.contains(pd) || self.config.unwatched.contains(pd) {
+                    continue;
                 }
-                Some(p) => {
-                    update_proc(&mut self.procs, p);
+                if let Some(p) = procs.get(pd) {
+                    if !keep(p) {
+                        continue;
+                    }
+                    let sel = match p.parent() {
+                        Some(pp) => format!("child of {}", pp),
+                        None => "child".to_string(),
+                    };
+                    update_proc(&mut self.procs, p, &sel, self.config.stitch, tm);
                 }
             }
         }
@@ -126,12 +605,137 @@ impl Layout {
     }
 
     pub(crate) fn update(&mut self) {
-        self.system.refresh_processes();
-        self.system.refresh_cpu();
-        self.system.refresh_memory();
+        if self.replay.is_some() {
+            self.update_replay();
+        } else {
+            self.reap_child();
+            self.system.refresh_processes();
+            self.system.refresh_cpu();
+            self.system.refresh_memory();
 
-        self.update_procs();
-        self.update_total();
+            let tm = SystemTime::now();
+            self.update_procs(tm);
+            self.update_total();
+            if let Some(rec) = self.recorder.as_mut() {
+                rec.tick(tm, self.cpu_usage, self.mem_usage, &self.procs);
+            }
+            if let Some(sd) = self.statsd.as_mut() {
+                sd.send(&self.procs, self.config.title_mode);
+            }
+        }
+
+        let keep = self.history_len();
+        for ap in self.procs.iter_mut() {
+            ap.set_keep(keep);
+        }
+        self.check_alerts();
+        self.check_leaks();
+        if let Some(exp) = self.exporter.as_ref() {
+            exp.update(&self.procs, self.config.title_mode, self.cpu_usage, self.mem_usage);
+        }
+        // Scrolled back graphs keep showing the same time interval
+        if self.offset != 0 {
+            self.offset = (self.offset + 1).min(self.max_offset());
+        }
+    }
+
+    // Checks the latest values of alive processes against alert rules and logs fired alerts
+    fn check_alerts(&mut self) {
+        if self.config.alerts.is_empty() {
+            return;
+        }
+        let tm = self.sample_time();
+        for ap in self.procs.iter_mut() {
+            if ap.dead {
+                clear_alerts(ap, &self.config);
+                continue;
+            }
+            for idx in ap.check_alerts(&self.config.alerts, tm) {
+                self.bell = true;
+                if let (Some(cmd), None) = (self.config.on_alert.as_ref(), self.replay.as_ref()) {
+                    let mut vars = hook::process_vars(ap, self.config.title_mode);
+                    vars.push(("PWATCH_ALERT", self.config.alerts[idx].text.clone()));
+                    if let Err(e) = self.hooks.run(cmd, &vars) {
+                        error!("Failed to run alert hook '{}': {}", cmd, e);
+                    }
+                }
+                warn!(
+                    "Alert '{}': process {} ({}), CPU {}%, MEM {} bytes",
+                    self.config.alerts[idx].text,
+                    ap.pid,
+                    ap.description(self.config.title_mode),
+                    ap.cpu.last(),
+                    ap.mem.last()
+                );
+            }
+        }
+    }
+
+    // Warns about processes which memory keeps growing longer than allowed
+    fn check_leaks(&mut self) {
+        let window = match self.config.leak_warn {
+            None => return,
+            Some(w) => w,
+        };
+        let tm = self.sample_time();
+        for ap in self.procs.iter_mut() {
+            if ap.dead || !ap.check_leak(window, tm) {
+                continue;
+            }
+            let rate = ap.mem_trend().unwrap_or(0.0);
+            warn!(
+                "Memory of process {} ({}) has been growing for {}: {:.0} bytes/h, MEM {} bytes",
+                ap.pid,
+                ap.description(self.config.title_mode),
+                format_duration(window),
+                rate,
+                ap.mem.last()
+            );
+        }
+    }
+
+    // Reports every process that has exited since the previous refresh: writes its exit to the
+    // record and runs the exit hook
+    fn handle_exits(&mut self) {
+        self.hooks.reap();
+        for ap in self.procs.iter_mut() {
+            if !ap.dead || ap.exit_handled {
+                continue;
+            }
+            ap.exit_handled = true;
+            clear_alerts(ap, &self.config);
+            if let Some(rec) = self.recorder.as_mut() {
+                rec.exited(ap);
+            }
+            if let Some(ref cmd) = self.config.on_exit {
+                let vars = hook::process_vars(ap, self.config.title_mode);
+                if let Err(e) = self.hooks.run(cmd, &vars) {
+                    error!("Failed to run exit hook '{}': {}", cmd, e);
+                }
+            }
+        }
+    }
+
+    // Returns true if an alert has fired since the previous call
+    pub(crate) fn take_bell(&mut self) -> bool {
+        std::mem::replace(&mut self.bell, false)
+    }
+
+    // Returns how many samples the graphs can be scrolled back by
+    fn max_offset(&self) -> usize {
+        let zoom = self.config.zoom;
+        let widest =
+            |ap: &Process| ap.cpu.kept().len().saturating_sub(ap.cpu.display_cnt.min(ap.mem.display_cnt) * zoom);
+        self.procs.iter().map(widest).max().unwrap_or(0)
+    }
+
+    // Scrolls the graphs back or forward in time by a quarter of the screen width. Returns true
+    // if the graphs have moved.
+    pub(crate) fn scroll_time(&mut self, back: bool) -> bool {
+        let step = (self.w as usize / 4).max(1) * self.config.zoom;
+        let old = self.offset;
+        self.offset = if back { (self.offset + step).min(self.max_offset()) } else { self.offset.saturating_sub(step) };
+        self.offset != old
     }
 
     // Recalculate position of all graphs. Mark ones that are out of screen.
@@ -177,11 +781,18 @@ impl Layout {
     where
         W: Write,
     {
-        if self.show_help {
+        let (matched, dropped) = self.prompt_preview();
+        if self.prompt.is_some() {
+            draw_prompt(w, self, matched, dropped.len())?;
+        } else if self.show_help {
             draw_help(w, self)?;
         } else {
             draw_totals(w, self)?;
         }
+        if self.show_stats {
+            return draw_stats(w, self);
+        }
+        let now = self.sample_time();
         for (idx, proc) in self.procs.iter_mut().enumerate() {
             if idx < self.top_item {
                 continue;
@@ -189,7 +800,8 @@ impl Layout {
             if proc.w == 0 {
                 break;
             }
-            draw_counter(w, proc, idx + 1, self.config.title_mode, &self.config)?;
+            proc.dropping = dropped.contains(&proc.pid);
+            draw_counter(w, proc, idx + 1, self.config.title_mode, self.offset, &self.config, now)?;
         }
         Ok(())
     }
@@ -239,7 +851,7 @@ impl Layout {
     pub(crate) fn toggle_mark(&mut self) {
         let is_off = self.mark_since.is_none();
         if is_off {
-            self.mark_since = Some(SystemTime::now());
+            self.mark_since = Some(self.sample_time());
         } else {
             self.mark_since = None;
         };
@@ -262,6 +874,65 @@ impl Layout {
         self.show_help = !self.show_help;
     }
 
+    // Writes the history of all watched processes to a file
+    pub(crate) fn export(&self, path: &str, fmt: Format) -> std::io::Result<()> {
+        export::export(path, fmt, &self.procs, self.config.title_mode)
+    }
+
+    // Exports the history to a new file by a hotkey. A failure must not stop pwatch: it is logged
+    // and shown in the top line as well as a success.
+    pub(crate) fn export_by_key(&mut self, fmt: Format) {
+        let path = export::file_name(fmt);
+        let msg = match self.export(&path, fmt) {
+            Ok(_) => {
+                info!("History exported to '{}'", path);
+                format!("Exported to {}", path)
+            }
+            Err(e) => {
+                error!("Failed to export history to '{}': {}", path, e);
+                format!("Export failed: {}", e)
+            }
+        };
+        self.status = Some((msg, Instant::now()));
+    }
+
+    // Returns the message about the latest hotkey action if it is still fresh
+    fn status(&self) -> Option<&str> {
+        match self.status {
+            Some((ref msg, tm)) if tm.elapsed() < STATUS_TIME => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn switch_stats(&mut self) {
+        self.show_stats = !self.show_stats;
+    }
+
+    // Prints a report about all watched processes
+    pub(crate) fn print_summary(&self, fmt: summary::Format) -> std::io::Result<()> {
+        let mut out = std::io::stdout();
+        summary::write(&mut out, &self.procs, self.config.title_mode, fmt, self.sample_time())?;
+        out.flush()
+    }
+
+    // Returns descriptions of processes which peak values have exceeded the limits set by option --fail-if
+    pub(crate) fn over_budget(&self) -> Vec<String> {
+        let mut res = Vec::new();
+        for p in self.procs.iter() {
+            for rule in p.over_budget(&self.config.fail_if) {
+                res.push(format!(
+                    "Budget '{}' exceeded: process {} ({}), peak CPU {}%, peak MEM {} bytes",
+                    rule.text,
+                    p.pid,
+                    p.description(self.config.title_mode),
+                    p.peak().0,
+                    p.peak().1
+                ));
+            }
+        }
+        res
+    }
+
     pub(crate) fn remove_dead(&mut self) -> bool {
         if self.procs.iter().all(|x| !x.dead) {
             return false;
@@ -271,53 +942,338 @@ impl Layout {
     }
 }
 
-fn update_proc<P>(procs: &mut Vec<Process>, p: &P)
+// Case-insensitive pattern to match a process by its path to binary, name, or command line. If
+// the pattern is not a valid regular expression, it is searched as a plain substring.
+struct Matcher {
+    rx: Option<Regex>,
+    low_flt: String,
+    on: MatchOn,
+}
+
+impl Matcher {
+    fn new(flt: &str, on: MatchOn) -> Matcher {
+        let rx = Regex::new(&format!("(?i){}", flt)).ok();
+        Matcher { rx, low_flt: flt.to_lowercase(), on }
+    }
+
+    fn is_match<P>(&self, p: &P) -> bool
+    where
+        P: ProcessExt,
+    {
+        let full_name = match self.on {
+            MatchOn::Exe => format!("{} {}", p.exe().to_string_lossy(), p.name()),
+            MatchOn::Cmd => cmd_line(p),
+            MatchOn::Name => p.name().to_string(),
+            MatchOn::All => format!("{} {} {}", p.exe().to_string_lossy(), p.name(), cmd_line(p)),
+        };
+        self.is_match_str(&full_name)
+    }
+
+    fn is_match_str(&self, name: &str) -> bool {
+        let low_name = name.to_lowercase();
+        if let Some(ref rr) = self.rx {
+            rr.is_match(&low_name)
+        } else {
+            low_name.contains(&self.low_flt)
+        }
+    }
+}
+
+// Checks whether the process is owned by the user: either by name or by UID. Any process is accepted
+// if the user is not set.
+fn owned_by<P>(system: &System, user: Option<&str>, p: &P) -> bool
 where
     P: ProcessExt,
 {
-    for ap in procs.iter_mut() {
+    let user = match user {
+        None => return true,
+        Some(u) => u,
+    };
+    let uid = match p.user_id() {
+        None => return false,
+        Some(uid) => uid,
+    };
+    if (**uid).to_string() == user {
+        return true;
+    }
+    match system.get_user_by_id(uid) {
+        Some(u) => u.name() == user,
+        None => false,
+    }
+}
+
+// Returns a check whether a process is neither excluded nor owned by another user
+fn accepts<'a>(
+    system: &'a System,
+    user: Option<&'a str>,
+    exclude: &'a [Matcher],
+) -> impl Fn(&sysinfo::Process) -> bool + 'a {
+    move |p| !exclude.iter().any(|m| m.is_match(p)) && owned_by(system, user, p)
+}
+
+// Returns the process command line with arguments separated by spaces
+fn cmd_line<P>(p: &P) -> String
+where
+    P: ProcessExt,
+{
+    p.cmd().join(" ")
+}
+
+// Reads PID from a pidfile. The file must start with the PID, the rest is ignored.
+fn read_pidfile(path: &str) -> Option<Pid> {
+    let content = fs::read_to_string(path).ok()?;
+    let first = content.split_whitespace().next()?;
+    first.parse::<u32>().ok().map(Pid::from_u32)
+}
+
+// Returns PIDs of all processes in a cgroup. The path is either a cgroup directory or its
+// `cgroup.procs` file.
+fn read_cgroup(path: &str) -> Vec<Pid> {
+    let mut procs_path = PathBuf::from(path);
+    if procs_path.is_dir() {
+        procs_path.push("cgroup.procs");
+    }
+    match fs::read_to_string(procs_path) {
+        Ok(content) => content.lines().filter_map(|l| l.trim().parse::<u32>().ok()).map(Pid::from_u32).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Returns PIDs of all descendants of `roots`. `tree` is a list of process PIDs with their parent PIDs.
+fn find_descendants(tree: &[(Pid, Option<Pid>)], roots: &HashSet<Pid>) -> Vec<Pid> {
+    let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+    for (pid, parent) in tree.iter() {
+        if let Some(pp) = parent {
+            children.entry(*pp).or_default().push(*pid);
+        }
+    }
+    let mut found: HashSet<Pid> = HashSet::new();
+    let mut queue: Vec<Pid> = roots.iter().copied().collect();
+    let mut res = Vec::new();
+    while let Some(pid) = queue.pop() {
+        if let Some(kids) = children.get(&pid) {
+            for kid in kids.iter() {
+                if roots.contains(kid) || !found.insert(*kid) {
+                    continue;
+                }
+                res.push(*kid);
+                queue.push(*kid);
+            }
+        }
+    }
+    res
+}
+
+// Checks whether any ancestor of the process is in the set
+fn has_ancestor(parents: &HashMap<Pid, Option<Pid>>, pid: Pid, set: &HashSet<Pid>) -> bool {
+    let mut curr = pid;
+    // The limit protects from endless loop if PIDs were reused and formed a cycle
+    for _ in 0..parents.len() {
+        match parents.get(&curr) {
+            Some(Some(pp)) => {
+                if set.contains(pp) {
+                    return true;
+                }
+                curr = *pp;
+            }
+            _ => return false,
+        }
+    }
+    false
+}
+
+// Refreshes combined usage of watched process trees. New descendants are added only if `keep` accepts
+// them. Returns all processes that belong to the trees.
+fn update_trees<F>(
+    aprocs: &mut [Process],
+    procs: &HashMap<Pid, sysinfo::Process>,
+    keep: &F,
+    tm: SystemTime,
+) -> HashSet<Pid>
+where
+    F: Fn(&sysinfo::Process) -> bool,
+{
+    let tree: Vec<(Pid, Option<Pid>)> = procs.values().map(|p| (p.pid(), p.parent())).collect();
+    let mut claimed: HashSet<Pid> = HashSet::new();
+    for ap in aprocs.iter_mut() {
         if ap.dead {
             continue;
         }
-        if ap.pid == p.pid() {
-            let prc: u64 = p.cpu_usage().round() as u64;
-            ap.add(prc, p.memory());
-            let du = p.disk_usage();
-            ap.io_w_total = du.total_written_bytes / 1024;
-            ap.io_r_total = du.total_read_bytes / 1024;
-            ap.io_w_delta = du.written_bytes / 1024;
-            ap.io_r_delta = du.read_bytes / 1024;
-            return;
+        let t = match ap.tree.as_mut() {
+            None => continue,
+            Some(t) => t,
+        };
+        // Start from all alive members: they may have been reparented after their parent exited
+        let roots: HashSet<Pid> =
+            t.members.keys().filter(|pd| procs.contains_key(pd) && !claimed.contains(pd)).copied().collect();
+        let mut fresh = HashMap::new();
+        let kids = find_descendants(&tree, &roots);
+        for pd in roots.iter().chain(kids.iter().filter(|pd| keep(&procs[pd]))) {
+            if claimed.insert(*pd) {
+                fresh.insert(*pd, sample_of(&procs[pd]));
+            }
+        }
+        let total = t.tick(fresh);
+        let alive = !t.members.is_empty();
+        ap.add_sample(&total, tm);
+        if !alive {
+            ap.dead = true;
+            ap.dead_since = Some(SystemTime::now());
         }
     }
+    claimed
+}
 
-    let mut cmd = String::new();
-    for s in p.cmd().iter() {
-        if !cmd.is_empty() {
-            cmd += " ";
+// Starts a new tree for every selected process that does not belong to any watched tree and
+// whose ancestors are not selected. Only descendants accepted by `keep` are added to the tree.
+fn start_trees<F>(
+    aprocs: &mut Vec<Process>,
+    procs: &HashMap<Pid, sysinfo::Process>,
+    selected: &[(Pid, String)],
+    claimed: &HashSet<Pid>,
+    keep: &F,
+    stitch: bool,
+    tm: SystemTime,
+) where
+    F: Fn(&sysinfo::Process) -> bool,
+{
+    let tree: Vec<(Pid, Option<Pid>)> = procs.values().map(|p| (p.pid(), p.parent())).collect();
+    let mut claimed = claimed.clone();
+    let parents: HashMap<Pid, Option<Pid>> = tree.iter().copied().collect();
+    let sel: HashSet<Pid> = selected.iter().map(|(pd, _)| *pd).collect();
+    for (pd, selector) in selected.iter() {
+        if claimed.contains(pd) || has_ancestor(&parents, *pd, &sel) {
+            continue;
         }
-        cmd += s;
+        let p = match procs.get(pd) {
+            None => continue,
+            Some(p) => p,
+        };
+        let roots: HashSet<Pid> = [*pd].iter().copied().collect();
+        let mut fresh = HashMap::new();
+        let kids = find_descendants(&tree, &roots);
+        for kid in roots.iter().chain(kids.iter().filter(|pd| keep(&procs[pd]))) {
+            if claimed.insert(*kid) {
+                fresh.insert(*kid, sample_of(&procs[kid]));
+            }
+        }
+        let mut t = Tree::default();
+        let total = t.tick(fresh);
+        if stitch {
+            if let Some(idx) = revive(aprocs, &cmd_line(p), p.pid(), true) {
+                aprocs[idx].tree = Some(t);
+                aprocs[idx].add_sample(&total, tm);
+                continue;
+            }
+        }
+        let mut ap = new_proc(p, selector);
+        ap.tree = Some(t);
+        ap.add_sample(&total, tm);
+        aprocs.push(ap);
+    }
+}
+
+// Returns the current resource usage of a process
+fn sample_of<P>(p: &P) -> Sample
+where
+    P: ProcessExt,
+{
+    let du = p.disk_usage();
+    Sample {
+        cpu: p.cpu_usage().round() as u64,
+        mem: p.memory(),
+        io_r_total: du.total_read_bytes / 1024,
+        io_w_total: du.total_written_bytes / 1024,
+        io_r_delta: du.read_bytes / 1024,
+        io_w_delta: du.written_bytes / 1024,
     }
+}
+
+// Creates a new watched process without any measurements
+fn new_proc<P>(p: &P, selector: &str) -> Process
+where
+    P: ProcessExt,
+{
+    let cmd = cmd_line(p);
     let exe = p.exe().to_string_lossy().to_string();
     let title = p.name().to_string();
 
     let mut ap = Process::new(p.pid(), cmd, exe, title);
-    let prc: u64 = p.cpu_usage().round() as u64;
-    ap.add(prc, p.memory());
-    let du = p.disk_usage();
-    ap.io_w_total = du.total_written_bytes / 1024;
-    ap.io_r_total = du.total_read_bytes / 1024;
+    ap.selector = selector.to_string();
+    ap
+}
+
+// Looks for a dead process with the same command line and attaches a restarted process to it, so
+// its graphs continue. Returns the index of the revived process.
+fn revive(procs: &mut [Process], cmd: &str, pid: Pid, tree: bool) -> Option<usize> {
+    if cmd.is_empty() {
+        return None;
+    }
+    let mut found: Option<usize> = None;
+    for (idx, ap) in procs.iter().enumerate() {
+        if !ap.dead || ap.cmd != cmd || ap.tree.is_some() != tree {
+            continue;
+        }
+        // the latest dead one wins
+        match found {
+            Some(f) if procs[f].dead_since >= ap.dead_since => {}
+            _ => found = Some(idx),
+        }
+    }
+    let idx = found?;
+    procs[idx].restart(pid);
+    Some(idx)
+}
+
+fn update_proc<P>(procs: &mut Vec<Process>, p: &P, selector: &str, stitch: bool, tm: SystemTime)
+where
+    P: ProcessExt,
+{
+    let sample = sample_of(p);
+    for ap in procs.iter_mut() {
+        if ap.dead {
+            continue;
+        }
+        if ap.pid == p.pid() {
+            ap.add_sample(&sample, tm);
+            return;
+        }
+    }
+
+    if stitch {
+        if let Some(idx) = revive(procs, &cmd_line(p), p.pid(), false) {
+            procs[idx].add_sample(&sample, tm);
+            return;
+        }
+    }
+    let mut ap = new_proc(p, selector);
+    ap.add_sample(&sample, tm);
     procs.push(ap);
 }
 
+// Resets alerts of an exited process, so it does not keep the alert in its title, and logs the
+// alerts that were firing as recovered
+fn clear_alerts(ap: &mut Process, conf: &Config) {
+    for idx in ap.clear_alerts() {
+        info!(
+            "Alert '{}' recovered: process {} ({}) has exited",
+            conf.alerts[idx].text,
+            ap.pid,
+            ap.description(conf.title_mode)
+        );
+    }
+}
+
 fn draw_help<W>(w: &mut W, layout: &Layout) -> Result<()>
 where
     W: Write,
 {
     // Keep the least useful keys at the end as they can be removed when squeezing the string to
     // screen width
-    let help_str = "SPACE Mark | F2 Shot | F6 Graph | F7 Quality | F8 Clean | F9 Title | F12 Scale | r Reset max";
-    let mut s = cut_string(help_str, layout.w as usize);
+    let help_str = "SPACE Mark | / Filter | \u{2190}\u{2192} Time | + - Zoom | F2 Shot | F3 CSV | F4 JSON | F6 Graph | F7 Quality | F8 Clean | F9 Title | F5 Stats | F12 Scale | r Reset max | a Aggregate";
+    let help_str =
+        if layout.is_replay() { format!("p Pause | n Step | < > Speed | {}", help_str) } else { help_str.to_string() };
+    let mut s = cut_string(&help_str, layout.w as usize);
     let width = s.width();
     if width < layout.w as usize {
         s += &" ".repeat(layout.w as usize - width);
@@ -332,13 +1288,99 @@ where
     )
 }
 
+// Draws the prompt in the top line. Processes that would be removed are highlighted in the same
+// colors by draw_title.
+fn draw_prompt<W>(w: &mut W, layout: &Layout, matched: usize, dropped: usize) -> Result<()>
+where
+    W: Write,
+{
+    let text = layout.prompt.clone().unwrap_or_default();
+    let tail = format!(" | Matches: {} | Removes: {} | Enter Apply | Esc Cancel", matched, dropped);
+    let width = layout.w as usize;
+    // Keep the end of a long expression visible: it is where a user types
+    let room = width.saturating_sub(tail.width() + "Filter: _".len());
+    let mut s = cut_string(&format!("Filter: {}_{}", fade_str_left(&text, room), tail), width);
+    let sw = s.width();
+    if sw < width {
+        s += &" ".repeat(width - sw);
+    }
+    queue!(
+        w,
+        cursor::MoveTo(0, 0),
+        style::SetForegroundColor(Color::Black),
+        style::SetBackgroundColor(Color::Yellow),
+        style::Print(s),
+        style::ResetColor
+    )
+}
+
+// Draws a table with statistics of CPU and memory usage of every process: two lines per process
+fn draw_stats<W>(w: &mut W, layout: &Layout) -> Result<()>
+where
+    W: Write,
+{
+    let width = layout.w as usize;
+    let scope = match layout.mark_since.and_then(|dt| layout.sample_time().duration_since(dt).ok()) {
+        Some(since) => format!("Statistics since mark: last {}", format_duration(since)),
+        None => {
+            let start = layout.procs.iter().filter_map(|p| p.first_seen).min();
+            match start.and_then(|tm| layout.sample_time().duration_since(tm).ok()) {
+                Some(since) => format!("Statistics for the whole session: {}", format_duration(since)),
+                None => "Statistics for the whole session".to_string(),
+            }
+        }
+    };
+    let head = format!(
+        "{:>3} {:>8} {:3} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}  TITLE",
+        "#", "PID", "", "MIN", "AVG", "P50", "P95", "P99", "MAX", "STDDEV"
+    );
+    let mut lines = vec![scope, head];
+    for (idx, p) in layout.procs.iter().enumerate().skip(layout.top_item) {
+        let (cpu, mem) = p.stats();
+        let cpu = match cpu {
+            None => "-".to_string(),
+            Some(st) => format!(
+                "{:>7} {:>7.1} {:>7} {:>7} {:>7} {:>7} {:>7.1}",
+                st.min, st.avg, st.p50, st.p95, st.p99, st.max, st.stddev
+            ),
+        };
+        let mem = match mem {
+            None => "-".to_string(),
+            Some(st) => format!(
+                "{:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}",
+                format_mem_bytes(st.min),
+                format_mem_bytes(st.avg.round() as u64),
+                format_mem_bytes(st.p50),
+                format_mem_bytes(st.p95),
+                format_mem_bytes(st.p99),
+                format_mem_bytes(st.max),
+                format_mem_bytes(st.stddev.round() as u64)
+            ),
+        };
+        lines.push(format!(
+            "{:>3} {:>8} CPU {}  {}",
+            idx + 1,
+            p.pid.to_string(),
+            cpu,
+            p.description(layout.config.title_mode)
+        ));
+        lines.push(format!("{:>3} {:>8} MEM {}", "", "", mem));
+    }
+    for y in 1..layout.h {
+        let line = lines.get(y as usize - 1).map(|l| cut_string(l, width)).unwrap_or_default();
+        let pad = width.saturating_sub(line.width());
+        queue!(w, cursor::MoveTo(0, y), style::Print(line), style::Print(" ".repeat(pad)))?;
+    }
+    Ok(())
+}
+
 fn draw_totals<W>(w: &mut W, layout: &Layout) -> Result<()>
 where
     W: Write,
 {
     let (t, h, d) = layout.proc_totals();
     let mut mark = if let Some(dt) = layout.mark_since {
-        match dt.elapsed() {
+        match layout.sample_time().duration_since(dt) {
             Err(_) => String::new(),
             Ok(since) => format_duration(since),
         }
@@ -359,8 +1401,441 @@ where
             layout.cpu_usage, layout.mem_usage, t, h, d, mark
         )
     };
+    if layout.offset != 0 {
+        let back = Duration::from_millis(layout.offset as u64 * layout.sample_interval());
+        title += &format!(" | Back {}", format_duration(back));
+    }
+    if layout.config.zoom != 1 {
+        let column = Duration::from_millis(layout.config.zoom as u64 * layout.sample_interval());
+        let reduce = format!("{:?}", layout.config.reduce).to_lowercase();
+        title += &format!(" | Column {} {}", format_duration(column), reduce);
+    }
+    if let Some(ref r) = layout.replay {
+        let state = if r.finished() {
+            "end".to_string()
+        } else if r.paused {
+            "paused".to_string()
+        } else {
+            format!("x{}", r.speed())
+        };
+        title += &format!(" | Replay {}", state);
+    }
+    if let Some(msg) = layout.status() {
+        title += &format!(" | {}", msg);
+    }
+    let mut title = cut_string(&title, layout.w as usize);
     if title.len() < layout.w as usize {
         title += &" ".repeat(layout.w as usize - title.len());
     }
     queue!(w, cursor::MoveTo(0, 0), style::Print(title))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matcher() {
+        let m = Matcher::new("java", MatchOn::Exe);
+        assert!(m.is_match_str("/usr/bin/java java"));
+        assert!(m.is_match_str("/opt/JAVA/bin/jsvc jsvc"));
+        assert!(!m.is_match_str("/usr/bin/python3 python3"));
+        let m = Matcher::new("^/opt/.*lsp", MatchOn::Exe);
+        assert!(m.is_match_str("/opt/ide/jdt-lsp java"));
+        assert!(!m.is_match_str("/usr/bin/jdt-lsp java"));
+        // invalid regular expression is a plain substring
+        let m = Matcher::new("c++(", MatchOn::Exe);
+        assert!(m.is_match_str("/usr/bin/C++(1) c"));
+        assert!(!m.is_match_str("/usr/bin/c++ c"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn matcher_on() {
+        let mut child = std::process::Command::new("sleep").arg("7.9").spawn().unwrap();
+        let pid = Pid::from_u32(child.id());
+        let mut sys = System::new();
+        // the child may not have started the command yet at the first refresh
+        for _ in 0..100 {
+            sys.refresh_processes();
+            if sys.process(pid).map(|p| p.name() == "sleep").unwrap_or(false) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let matches = |flt: &str, on: MatchOn| sys.process(pid).map(|p| Matcher::new(flt, on).is_match(p));
+        let res = [
+            matches("^sleep$", MatchOn::Name),
+            matches("^sleep$", MatchOn::Exe),
+            matches("7\\.9", MatchOn::Name),
+            matches("sleep.*7\\.9", MatchOn::All),
+            matches("7\\.9", MatchOn::Exe),
+        ];
+        let _ = child.kill();
+        let _ = child.wait();
+        // the name alone, without the executable path
+        assert_eq!(res[0], Some(true));
+        assert_eq!(res[1], Some(false));
+        assert_eq!(res[2], Some(false));
+        // the executable, the name, and the command line
+        assert_eq!(res[3], Some(true));
+        assert_eq!(res[4], Some(false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn user() {
+        use std::os::unix::fs::MetadataExt;
+
+        let uid = fs::metadata("/proc/self").unwrap().uid();
+        let out = std::process::Command::new("id").arg("-un").output().unwrap();
+        let name = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        let mut lay = Layout::new(Config::default());
+        let me = sysinfo::get_current_pid().unwrap();
+        let cases = [
+            (None, true),
+            (Some(uid.to_string()), true),
+            (Some(name), true),
+            (Some((uid + 1).to_string()), false),
+            (Some("no-such-user".to_string()), false),
+        ];
+        for (user, res) in cases {
+            lay.config.user = user;
+            assert_eq!(lay.owned_by_user(lay.system.process(me).unwrap()), res);
+        }
+    }
+
+    #[test]
+    fn replayed_mark() {
+        let path = std::env::temp_dir().join(format!("pwatch-test-mark-{}.rec", std::process::id()));
+        let content = format!(
+            "{}\nT\t1000\t3\t40\nP\t1000\t42\tsrv\t\t\nS\t1000\t42\t10\t2048\t5\t0\t0\t0\n\
+             T\t3000\t4\t41\nS\t3000\t42\t20\t4096\t5\t0\t0\t0\nT\t5000\t4\t41\n",
+            crate::record::HEADER
+        );
+        fs::write(&path, content).unwrap();
+        let replay = Replay::load(&path.to_string_lossy()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut lay = Layout::new(Config::default());
+        lay.w = 100;
+        lay.set_replay(replay);
+        lay.update();
+        lay.toggle_mark();
+        lay.update();
+        lay.update();
+        let mut out: Vec<u8> = Vec::new();
+        draw_stats(&mut out, &lay).unwrap();
+        // the mark is in the recorded time, not in the wall-clock one
+        assert!(String::from_utf8(out).unwrap().contains("Statistics since mark: last 4s"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finish_child() {
+        use std::process::Command;
+
+        // a command that has exited by itself reports its status
+        let mut lay = Layout::new(Config::default());
+        let mut child = Command::new("sh").arg("-c").arg("exit 4").spawn().unwrap();
+        let st = child.wait().unwrap();
+        lay.set_child(child);
+        lay.child_status = Some(st);
+        assert_eq!(lay.finish_child().and_then(|st| st.code()), Some(4));
+
+        // a running command is asked to terminate, and it is not an error
+        let mut lay = Layout::new(Config::default());
+        lay.set_child(Command::new("sleep").arg("7.95").spawn().unwrap());
+        let start = Instant::now();
+        assert!(lay.finish_child().is_none());
+        assert!(start.elapsed() < STOP_GRACE);
+
+        // a command that ignores SIGTERM is killed after a while
+        let mut lay = Layout::new(Config::default());
+        let child = Command::new("sh").arg("-c").arg("trap '' TERM; sleep 7.96 & wait").spawn().unwrap();
+        let pid = Pid::from_u32(child.id());
+        lay.set_child(child);
+        // wait for the shell to set up the trap
+        std::thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        assert!(lay.finish_child().is_none());
+        assert!(start.elapsed() >= STOP_GRACE);
+        assert!(!lay.system.refresh_process(pid));
+    }
+
+    #[test]
+    fn unchanged_prompt() {
+        let mut child = std::process::Command::new("sleep").arg("7.35").spawn().unwrap();
+        let pid = Pid::from_u32(child.id());
+        let mut lay = Layout::new(Config::default());
+        lay.config.match_on = MatchOn::Cmd;
+        // the second expression is invalid and matched as a plain substring
+        let filters = vec!["sleep 7\\.35".to_string(), "(x".to_string()];
+        lay.config.filters = filters.clone();
+        // the child may not have started the command yet at the first refresh
+        for _ in 0..100 {
+            lay.update();
+            if lay.procs.iter().any(|p| p.pid == pid) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        lay.open_prompt();
+        assert_eq!(lay.prompt_preview(), (1, HashSet::new()));
+        lay.apply_prompt();
+        let selector = lay.procs.iter().find(|p| p.pid == pid).map(|p| p.selector.clone());
+        let _ = child.kill();
+        let _ = child.wait();
+        // Enter without editing changes nothing
+        assert_eq!(lay.config.filters, filters);
+        assert_eq!(selector.as_deref(), Some("sleep 7\\.35"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_prompt() {
+        use std::process::Command;
+
+        let mut child = Command::new("sleep").arg("7.25").spawn().unwrap();
+        // a shell that waits for its own child, watched with option --children
+        let mut parent = Command::new("sh").arg("-c").arg("sleep 7.5; :").spawn().unwrap();
+        let ppid = Pid::from_u32(parent.id());
+        let mut lay = Layout::new(Config::default());
+        let mut kid = None;
+        for _ in 0..100 {
+            lay.system.refresh_processes();
+            kid = lay.system.processes().values().find(|p| p.parent() == Some(ppid)).map(|p| p.pid());
+            if kid.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let kid = kid.unwrap();
+        lay.config.match_on = MatchOn::Cmd;
+        lay.config.children = true;
+        lay.config.filters = vec!["old".to_string()];
+        let mk = |pid: u32, sel: &str| {
+            let mut p = Process::new(Pid::from_u32(pid), String::new(), String::new(), String::new());
+            p.selector = sel.to_string();
+            p
+        };
+        let mut gone = mk(4_000_003, "old");
+        gone.dead = true;
+        lay.procs = vec![
+            mk(child.id(), "old"),
+            mk(4_000_001, "old"),
+            mk(4_000_002, "4000002"),
+            gone,
+            mk(parent.id(), "old"),
+            mk(kid.as_u32(), &format!("child of {}", ppid)),
+        ];
+        lay.procs[0].add(10, 100);
+
+        lay.open_prompt();
+        assert_eq!(lay.prompt.as_deref(), Some("old"));
+        lay.edit_prompt("x".to_string());
+        lay.close_prompt();
+        assert_eq!(lay.config.filters, vec!["old".to_string()]);
+        assert_eq!(lay.procs.len(), 6);
+
+        lay.open_prompt();
+        lay.edit_prompt("sleep 7\\.25".to_string());
+        // the preview shows what Enter would do: the child of a removed process goes with it
+        let (matched, dropped) = lay.prompt_preview();
+        assert_eq!(matched, 1);
+        let expected: HashSet<Pid> = [Pid::from_u32(4_000_001), ppid, kid].iter().copied().collect();
+        assert_eq!(dropped, expected);
+        lay.apply_prompt();
+        let _ = Command::new("kill").arg(kid.to_string()).status();
+        for p in [&mut child, &mut parent].iter_mut() {
+            let _ = p.kill();
+            let _ = p.wait();
+        }
+        assert_eq!(lay.prompt, None);
+        assert_eq!(lay.config.filters, vec!["sleep 7\\.25".to_string()]);
+        // processes brought in by other selectors and dead ones are kept
+        let pids: Vec<u32> = lay.procs.iter().map(|p| p.pid.as_u32()).collect();
+        assert_eq!(pids, vec![child.id(), 4_000_002, 4_000_003]);
+        // the process that still matches keeps its history
+        assert_eq!(lay.procs[0].mem.last(), 100);
+        assert_eq!(lay.procs[0].selector, "sleep 7\\.25");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unwatch() {
+        let mut child = std::process::Command::new("sleep").arg("7.5").spawn().unwrap();
+        let pid = Pid::from_u32(child.id());
+        let mut lay = Layout::new(Config::default());
+        lay.config.match_on = MatchOn::Cmd;
+        lay.config.filters = vec!["sleep 7\\.5".to_string()];
+        let watched = |lay: &Layout| lay.procs.iter().any(|p| p.pid == pid && !p.dead);
+        lay.update();
+        assert!(watched(&lay));
+
+        assert!(lay.run_command(&Command::Unwatch(pid)).is_ok());
+        assert!(!watched(&lay));
+        assert!(lay.run_command(&Command::Unwatch(pid)).is_err());
+        // the filter still matches, but the process is not brought back
+        lay.update();
+        assert!(!watched(&lay));
+
+        assert!(lay.run_command(&Command::Watch(pid)).is_ok());
+        lay.update();
+        assert!(watched(&lay));
+
+        assert!(lay.run_command(&Command::Unwatch(pid)).is_ok());
+        assert_eq!(lay.config.unwatched, vec![pid]);
+        let _ = child.kill();
+        let _ = child.wait();
+        // the PID is free to be selected again after the process has exited
+        lay.update();
+        assert!(lay.config.unwatched.is_empty());
+    }
+
+    #[test]
+    fn watch_new() {
+        let mut lay = Layout::new(Config::default());
+        lay.update();
+        // the process starts after the latest refresh
+        let mut child = std::process::Command::new("sleep").arg("7.3").spawn().unwrap();
+        let pid = Pid::from_u32(child.id());
+        let res = lay.run_command(&Command::Watch(pid));
+        lay.update();
+        let watched = lay.procs.iter().any(|p| p.pid == pid && !p.dead);
+        let _ = child.kill();
+        let _ = child.wait();
+        assert!(res.is_ok());
+        assert!(watched);
+    }
+
+    #[test]
+    fn exited_alerts() {
+        let mut child = std::process::Command::new("sleep").arg("7.7").spawn().unwrap();
+        let pid = Pid::from_u32(child.id());
+        let mut lay = Layout::new(Config::default());
+        lay.config.match_on = MatchOn::Cmd;
+        lay.config.filters = vec!["sleep 7\\.7".to_string()];
+        lay.config.alerts = vec![crate::alert::Rule::parse("mem>0").unwrap()];
+        let firing = |lay: &Layout| lay.procs.iter().any(|p| p.pid == pid && p.alerts.iter().any(|w| w.firing));
+        // the child may not have started the command yet at the first refresh
+        for _ in 0..100 {
+            lay.update();
+            if firing(&lay) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(firing(&lay));
+        let _ = child.kill();
+        let _ = child.wait();
+        // the process that has exited does not keep the alert
+        lay.update();
+        assert!(lay.procs.iter().any(|p| p.pid == pid && p.dead));
+        assert!(!firing(&lay));
+    }
+
+    #[test]
+    fn pidfile() {
+        let path = std::env::temp_dir().join(format!("pwatch-test-{}.pid", std::process::id()));
+        let spath = path.to_string_lossy().to_string();
+        assert_eq!(read_pidfile(&spath), None);
+        fs::write(&path, "1234\n").unwrap();
+        assert_eq!(read_pidfile(&spath), Some(Pid::from_u32(1234)));
+        // a daemon restarted and rewrote its pidfile
+        fs::write(&path, "  5678 extra\n").unwrap();
+        assert_eq!(read_pidfile(&spath), Some(Pid::from_u32(5678)));
+        fs::write(&path, "").unwrap();
+        assert_eq!(read_pidfile(&spath), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cgroup() {
+        let dir = std::env::temp_dir().join(format!("pwatch-test-cgroup-{}", std::process::id()));
+        let sdir = dir.to_string_lossy().to_string();
+        assert!(read_cgroup(&sdir).is_empty());
+        fs::create_dir_all(&dir).unwrap();
+        let procs = dir.join("cgroup.procs");
+        fs::write(&procs, "10\n20\n\n30\n").unwrap();
+        let pids: Vec<Pid> = [10, 20, 30].iter().map(|p| Pid::from_u32(*p)).collect();
+        assert_eq!(read_cgroup(&sdir), pids);
+        assert_eq!(read_cgroup(&procs.to_string_lossy()), pids);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tree_exclude() {
+        let mut sh = std::process::Command::new("sh").arg("-c").arg("sleep 7.41 & sleep 7.42 & wait").spawn().unwrap();
+        let mut lay = Layout::new(Config::default());
+        lay.config.match_on = MatchOn::Cmd;
+        lay.config.tree = true;
+        lay.config.filters = vec!["^sh -c sleep 7\\.41".to_string()];
+        lay.config.exclude = vec!["^sleep 7\\.42".to_string()];
+        let kid = |lay: &Layout, cmd: &str| {
+            lay.system.processes().values().find(|p| p.cmd().join(" ") == cmd).map(|p| p.pid())
+        };
+        // the shell may not have started both commands yet at the first refresh
+        for _ in 0..100 {
+            lay.update();
+            if kid(&lay, "sleep 7.41").is_some() && kid(&lay, "sleep 7.42").is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        lay.update();
+        let (kept, excluded) = (kid(&lay, "sleep 7.41").unwrap(), kid(&lay, "sleep 7.42").unwrap());
+        let _ = std::process::Command::new("kill").arg(kept.to_string()).arg(excluded.to_string()).status();
+        let _ = sh.wait();
+        assert_eq!(lay.procs.len(), 1);
+        let members = &lay.procs[0].tree.as_ref().unwrap().members;
+        assert!(members.contains_key(&kept));
+        assert!(!members.contains_key(&excluded));
+    }
+
+    #[test]
+    fn descendants() {
+        let p = Pid::from_u32;
+        let tree = vec![
+            (p(1), None),
+            (p(10), Some(p(1))),
+            (p(11), Some(p(10))),
+            (p(12), Some(p(11))),
+            (p(13), Some(p(10))),
+            (p(20), Some(p(1))),
+            (p(21), Some(p(20))),
+        ];
+        let roots: HashSet<Pid> = [p(10)].iter().copied().collect();
+        let mut res = find_descendants(&tree, &roots);
+        res.sort();
+        assert_eq!(res, vec![p(11), p(12), p(13)]);
+
+        let roots: HashSet<Pid> = [p(10), p(11), p(21)].iter().copied().collect();
+        let mut res = find_descendants(&tree, &roots);
+        res.sort();
+        assert_eq!(res, vec![p(12), p(13)]);
+
+        let roots: HashSet<Pid> = [p(12)].iter().copied().collect();
+        assert!(find_descendants(&tree, &roots).is_empty());
+    }
+
+    #[test]
+    fn ancestors() {
+        let p = Pid::from_u32;
+        let parents: HashMap<Pid, Option<Pid>> =
+            [(p(1), None), (p(10), Some(p(1))), (p(11), Some(p(10))), (p(12), Some(p(11))), (p(20), Some(p(1)))]
+                .iter()
+                .copied()
+                .collect();
+        let set: HashSet<Pid> = [p(10), p(20)].iter().copied().collect();
+        assert!(has_ancestor(&parents, p(12), &set));
+        assert!(has_ancestor(&parents, p(11), &set));
+        assert!(!has_ancestor(&parents, p(10), &set));
+        assert!(!has_ancestor(&parents, p(20), &set));
+        assert!(!has_ancestor(&parents, p(1), &set));
+
+        // PID reuse may create a loop
+        let parents: HashMap<Pid, Option<Pid>> = [(p(5), Some(p(6))), (p(6), Some(p(5)))].iter().copied().collect();
+        assert!(!has_ancestor(&parents, p(5), &set));
+    }
+}
@@ -1,21 +1,37 @@
+mod alert;
+mod batch;
 mod config;
+mod control;
 mod counter;
+mod export;
+mod hook;
 mod layout;
+mod metrics;
+mod record;
 mod shot;
+mod stats;
+mod statsd;
+mod summary;
 mod ux;
 
-// use simplelog::*;
-// use std::fs::File;
+use simplelog::*;
+use std::fs::File;
 
 use std::io::{stdout, Write};
-use std::process::exit;
-use std::time::{Duration, Instant};
+use std::process::{exit, Child, Command, ExitStatus, Stdio};
+use std::time::Instant;
 
 use atty::Stream;
 
 use crossterm::event::{poll, read, Event, KeyCode};
 use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode, ClearType};
-use crossterm::{queue, style, style::Color, Result};
+use crossterm::{execute, queue, style, style::Color, Result};
+use sysinfo::{Pid, PidExt};
+
+// Exit code when a process has exceeded a limit set by option --fail-if. It takes precedence over
+// the exit code of the started command, which can be 3 as well: only the report on stderr tells
+// the two cases apart.
+const BUDGET_EXIT_CODE: i32 = 3;
 
 fn process_events(lay: &mut layout::Layout) -> Result<()> {
     let mut tm = Instant::now();
@@ -35,12 +51,19 @@ fn process_events(lay: &mut layout::Layout) -> Result<()> {
         let (tot, hid, _dead) = lay.proc_totals();
         let page = tot - hid;
         let mut do_shot = false;
-        if poll(Duration::from_millis(lay.config.freq))? {
+        let mut step = false;
+        if poll(lay.update_interval())? {
             match read()? {
+                Event::Key(ev) if lay.prompt.is_some() => {
+                    prompt_key(lay, ev.code);
+                    force_redraw = true;
+                }
                 Event::Key(ev) => match ev.code {
                     KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
                     KeyCode::Down => force_redraw = lay.scroll(layout::Scroll::Down(1)),
                     KeyCode::Up => force_redraw = lay.scroll(layout::Scroll::Up(1)),
+                    KeyCode::Left => force_redraw = lay.scroll_time(true),
+                    KeyCode::Right => force_redraw = lay.scroll_time(false),
                     KeyCode::Home => force_redraw = lay.scroll(layout::Scroll::Home),
                     KeyCode::End => force_redraw = lay.scroll(layout::Scroll::End),
                     KeyCode::PageDown => force_redraw = lay.scroll(layout::Scroll::Down(page)),
@@ -49,6 +72,23 @@ fn process_events(lay: &mut layout::Layout) -> Result<()> {
                         lay.toggle_mark();
                         force_redraw = true;
                     }
+                    KeyCode::Char('/') if !lay.is_replay() => {
+                        lay.open_prompt();
+                        force_redraw = true;
+                    }
+                    KeyCode::Char('p') => force_redraw = lay.toggle_pause(),
+                    KeyCode::Char('n') if lay.is_replay() => {
+                        step = true;
+                        force_redraw = true;
+                    }
+                    KeyCode::Char('>') => force_redraw = lay.change_speed(true),
+                    KeyCode::Char('<') => force_redraw = lay.change_speed(false),
+                    KeyCode::Char('+') => force_redraw = lay.config.change_zoom(false),
+                    KeyCode::Char('-') => force_redraw = lay.config.change_zoom(true),
+                    KeyCode::Char('a') => {
+                        lay.config.switch_reduce();
+                        force_redraw = true;
+                    }
                     KeyCode::Char('r') => {
                         lay.reset_max();
                         force_redraw = true;
@@ -61,6 +101,19 @@ fn process_events(lay: &mut layout::Layout) -> Result<()> {
                         do_shot = true;
                         force_redraw = true;
                     }
+                    KeyCode::F(3) => {
+                        lay.export_by_key(export::Format::Csv);
+                        force_redraw = true;
+                    }
+                    KeyCode::F(4) => {
+                        lay.export_by_key(export::Format::Json);
+                        force_redraw = true;
+                    }
+                    KeyCode::F(5) => {
+                        lay.switch_stats();
+                        force_redraw = true;
+                        resized = true; // to clear the screen
+                    }
                     KeyCode::F(6) => {
                         lay.config.switch_graphs();
                         force_redraw = true;
@@ -95,13 +148,19 @@ fn process_events(lay: &mut layout::Layout) -> Result<()> {
                 _ => {}
             }
         }
-        let must_update = tm.elapsed() >= Duration::from_millis(lay.config.freq);
+        if lay.run_commands() {
+            force_redraw = true;
+        }
+        let must_update = step || (!lay.paused() && tm.elapsed() >= lay.update_interval());
         if !force_redraw && !must_update {
             continue;
         }
         force_redraw = false;
         if must_update {
             lay.update();
+            if lay.take_bell() {
+                execute!(stdout(), style::Print('\x07'))?;
+            }
         }
         lay.place();
 
@@ -128,14 +187,212 @@ fn process_events(lay: &mut layout::Layout) -> Result<()> {
     }
 }
 
+// Handles a key while the prompt to edit the regular expression is open
+fn prompt_key(lay: &mut layout::Layout, code: KeyCode) {
+    let mut text = lay.prompt.clone().unwrap_or_default();
+    match code {
+        KeyCode::Esc => lay.close_prompt(),
+        KeyCode::Enter => lay.apply_prompt(),
+        KeyCode::Backspace => {
+            text.pop();
+            lay.edit_prompt(text);
+        }
+        KeyCode::Char(c) => {
+            text.push(c);
+            lay.edit_prompt(text);
+        }
+        _ => {}
+    }
+}
+
+// Starts the command that pwatch should watch. Its output is discarded to keep the screen intact.
+// In batch mode, the command output is shown: it does not break the text output. With option --json,
+// only the command errors are shown to keep stdout a valid stream of JSON lines.
+fn spawn(cmd: &[String], batch: bool, json: bool) -> std::result::Result<Child, String> {
+    let mut command = Command::new(&cmd[0]);
+    let (stdout, stderr) = match (batch, json) {
+        (false, _) => (Stdio::null(), Stdio::null()),
+        (true, false) => (Stdio::inherit(), Stdio::inherit()),
+        (true, true) => (Stdio::null(), Stdio::inherit()),
+    };
+    command.args(&cmd[1..]).stdin(Stdio::null()).stdout(stdout).stderr(stderr);
+    command.spawn().map_err(|e| format!("Failed to start '{}': {}", cmd[0], e))
+}
+
+// Converts the exit status of the started command to pwatch exit code. A process killed by a
+// signal gets the code as in shells: 128 + signal number.
+fn exit_code(st: ExitStatus) -> i32 {
+    if let Some(code) = st.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(sig) = st.signal() {
+            return 128 + sig;
+        }
+    }
+    1
+}
+
+// Creates the layout, opens the record file, and starts the process to watch if pwatch runs in
+// spawn mode
+fn new_layout(mut config: config::Config) -> std::result::Result<layout::Layout, String> {
+    let recorder = match config.record {
+        None => None,
+        Some(ref path) => match record::Recorder::create(path) {
+            Ok(rec) => Some(rec),
+            Err(e) => return Err(format!("Failed to create record file '{}': {}", path, e)),
+        },
+    };
+    let exporter = match config.listen {
+        None => None,
+        Some(ref addr) => match metrics::Exporter::start(addr) {
+            Ok(exp) => {
+                log::info!("Serving metrics at http://{}/metrics", exp.addr);
+                Some(exp)
+            }
+            Err(e) => return Err(format!("Failed to listen on '{}': {}", addr, e)),
+        },
+    };
+    let statsd = match config.statsd {
+        None => None,
+        Some(ref addr) => match statsd::StatsD::connect(addr, &config.statsd_prefix) {
+            Ok(sd) => Some(sd),
+            Err(e) => return Err(format!("Failed to connect to StatsD '{}': {}", addr, e)),
+        },
+    };
+    let control = match config.control {
+        None => None,
+        Some(ref path) => match control::Control::start(path) {
+            Ok(ctl) => Some(ctl),
+            Err(e) => return Err(format!("Failed to create control socket '{}': {}", path, e)),
+        },
+    };
+    let replay = match config.replay {
+        None => None,
+        Some(ref path) => match record::Replay::load(path) {
+            Ok(rp) => Some(rp),
+            Err(e) => return Err(format!("Failed to load recorded session '{}': {}", path, e)),
+        },
+    };
+    let child = if config.spawn.is_empty() || replay.is_some() {
+        None
+    } else {
+        let child = spawn(&config.spawn, config.batch, config.json)?;
+        config.pid_list.push(Pid::from_u32(child.id()));
+        Some(child)
+    };
+    let mut lay = layout::Layout::new(config);
+    if let Some(child) = child {
+        lay.set_child(child);
+    }
+    if let Some(rec) = recorder {
+        lay.set_recorder(rec);
+    }
+    if let Some(rp) = replay {
+        lay.set_replay(rp);
+    }
+    if let Some(exp) = exporter {
+        lay.set_exporter(exp);
+    }
+    if let Some(sd) = statsd {
+        lay.set_statsd(sd);
+    }
+    if let Some(ctl) = control {
+        lay.set_control(ctl);
+    }
+    Ok(lay)
+}
+
+fn report_record_error(lay: &layout::Layout) {
+    if let Some(e) = lay.record_error() {
+        eprintln!("Recording stopped because of an error: {}", e);
+    }
+}
+
+// Prints the report about all processes if it is enabled by option --summary
+fn print_summary(lay: &layout::Layout) {
+    if let Some(fmt) = lay.config.summary {
+        if let Err(e) = lay.print_summary(fmt) {
+            eprintln!("Failed to print summary: {}", e);
+        }
+    }
+}
+
+// Writes the history of all processes to the file set by option --export
+fn export_history(lay: &layout::Layout) {
+    if let Some(ref path) = lay.config.export {
+        if let Err(e) = lay.export(path, export::Format::from_path(path)) {
+            eprintln!("Failed to export history to '{}': {}", path, e);
+        }
+    }
+}
+
+// Reports processes that have exceeded limits set by option --fail-if. Returns true if any has.
+fn check_budget(lay: &layout::Layout) -> bool {
+    let over = lay.over_budget();
+    for msg in over.iter() {
+        eprintln!("{}", msg);
+        log::warn!("{}", msg);
+    }
+    !over.is_empty()
+}
+
+fn run_batch(config: config::Config) {
+    let mut lay = match new_layout(config) {
+        Ok(lay) => lay,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+    if let Err(e) = batch::run(&mut lay) {
+        eprintln!("{}", e);
+    }
+    report_record_error(&lay);
+    export_history(&lay);
+    print_summary(&lay);
+    let over_budget = check_budget(&lay);
+    let status = lay.finish_child();
+    // exit() does not run destructors, so the control socket must be removed before it
+    drop(lay);
+    if over_budget {
+        exit(BUDGET_EXIT_CODE);
+    }
+    if let Some(st) = status {
+        exit(exit_code(st));
+    }
+}
+
+// Starts writing events to the file set by option --log
+fn init_log(path: &str) {
+    let f = match File::create(path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Failed to create log file '{}': {}", path, e);
+            exit(2);
+        }
+    };
+    let cb = ConfigBuilder::new().set_time_format_rfc3339().build();
+    if let Err(e) = WriteLogger::init(LevelFilter::Info, cb, f) {
+        eprintln!("Failed to initialize log: {}", e);
+    }
+}
+
 fn main() -> Result<()> {
+    let config = config::parse_args();
+    if let Some(ref path) = config.log {
+        init_log(path);
+    }
+    if config.batch {
+        run_batch(config);
+        return Ok(());
+    }
     if !atty::is(Stream::Stdout) {
-        eprintln!("Only TTY is supported");
+        eprintln!("Only TTY is supported. Use option --batch for non-interactive output");
         exit(2);
     }
-    // let cb = ConfigBuilder::new().set_time_format("[%Y-%m-%d %H:%M:%S%.3f]".to_string()).build();
-    // CombinedLogger::init(vec![WriteLogger::new(LevelFilter::Info, cb, File::create("app.log").unwrap())]).unwrap();
-    let config = config::parse_args();
     println!();
     enable_raw_mode()?;
     if let Ok((cols, rows)) = terminal::size() {
@@ -158,12 +415,63 @@ fn main() -> Result<()> {
         )?;
         stdout.flush()?;
     }
-    let mut lay = layout::Layout::new(config);
+    let mut lay = match new_layout(config) {
+        Ok(lay) => lay,
+        Err(e) => {
+            disable_raw_mode()?;
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
 
     if let Err(e) = process_events(&mut lay) {
         eprintln!("{:?}", e);
     }
 
     disable_raw_mode()?;
+    report_record_error(&lay);
+    export_history(&lay);
+    print_summary(&lay);
+    let over_budget = check_budget(&lay);
+    let status = lay.finish_child();
+    // exit() does not run destructors, so the control socket must be removed before it
+    drop(lay);
+    if over_budget {
+        exit(BUDGET_EXIT_CODE);
+    }
+    if let Some(st) = status {
+        exit(exit_code(st));
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(s: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), s.to_string()]
+    }
+
+    #[test]
+    fn spawn_exit_code() {
+        let mut child = spawn(&cmd("exit 5"), true, false).unwrap();
+        assert_eq!(exit_code(child.wait().unwrap()), 5);
+        let mut child = spawn(&cmd("true"), false, false).unwrap();
+        assert_eq!(exit_code(child.wait().unwrap()), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_killed() {
+        // a process killed by a signal exits with 128 + signal number, as in shells
+        let mut child = spawn(&cmd("kill -9 $$"), true, false).unwrap();
+        assert_eq!(exit_code(child.wait().unwrap()), 137);
+    }
+
+    #[test]
+    fn spawn_missing() {
+        let err = spawn(&["pwatch-no-such-command".to_string()], true, false).unwrap_err();
+        assert!(err.starts_with("Failed to start 'pwatch-no-such-command': "));
+    }
+}
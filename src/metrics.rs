@@ -0,0 +1,198 @@
+/*
+ * Prometheus endpoint(option --listen). A background thread serves the latest values of watched
+ * processes at `/metrics` in the text exposition format. The page is rebuilt at every refresh.
+ * Until the first refresh there is nothing to report, and `/metrics` answers 503.
+ */
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TitleMode;
+use crate::counter::Process;
+
+// Returns the value of a metric for a process
+type Value = fn(&Process) -> u64;
+
+pub(crate) struct Exporter {
+    pub(crate) addr: SocketAddr,
+    page: Arc<Mutex<String>>,
+}
+
+// Escapes a label value: backslashes, double quotes, and line breaks
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Builds the page with the system totals and one set of gauges per process
+fn render(procs: &[Process], mode: TitleMode, cpu_usage: u64, mem_usage: u64) -> String {
+    let mut page = String::new();
+    page += "# HELP pwatch_cpu_usage_percent Total CPU usage of the system.\n";
+    page += "# TYPE pwatch_cpu_usage_percent gauge\n";
+    page += &format!("pwatch_cpu_usage_percent {}\n", cpu_usage);
+    page += "# HELP pwatch_mem_usage_percent Total memory usage of the system.\n";
+    page += "# TYPE pwatch_mem_usage_percent gauge\n";
+    page += &format!("pwatch_mem_usage_percent {}\n", mem_usage);
+
+    // A process that has exited and a new one that reuses its PID and title have the same labels, and
+    // Prometheus rejects a page with duplicate series: keep the live process, or the latest dead one.
+    let mut series: Vec<(&Process, String)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for p in procs.iter() {
+        let l = format!("{{pid=\"{}\",title=\"{}\"}}", p.pid, label(&p.description(mode)));
+        match index.get(&l) {
+            None => {
+                index.insert(l.clone(), series.len());
+                series.push((p, l));
+            }
+            Some(&idx) => {
+                let shown = series[idx].0;
+                if shown.dead && (!p.dead || p.dead_since > shown.dead_since) {
+                    series[idx].0 = p;
+                }
+            }
+        }
+    }
+    let metrics: [(&str, &str, &str, Value); 5] = [
+        ("pwatch_process_cpu_percent", "gauge", "CPU usage of a process.", |p| p.cpu.last()),
+        ("pwatch_process_resident_memory_bytes", "gauge", "Resident memory of a process.", |p| p.mem.last()),
+        ("pwatch_process_io_read_bytes_total", "counter", "Total bytes read by a process.", |p| p.io_r_total * 1024),
+        ("pwatch_process_io_write_bytes_total", "counter", "Total bytes written by a process.", |p| {
+            p.io_w_total * 1024
+        }),
+        ("pwatch_process_dead", "gauge", "1 if a process has exited.", |p| u64::from(p.dead)),
+    ];
+    for (name, kind, help, value) in metrics.iter() {
+        page += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+        for (p, l) in series.iter() {
+            page += &format!("{}{} {}\n", name, l, value(p));
+        }
+    }
+    page
+}
+
+// Reads the request head and sends the page back. Only `GET /metrics` is supported.
+fn handle(mut stream: TcpStream, page: &Mutex<String>) -> io::Result<()> {
+    // A client that stops reading must not block the server thread
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(2)))?;
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") && req.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        req.extend_from_slice(&buf[..n]);
+    }
+    let req = String::from_utf8_lossy(&req);
+    let mut parts = req.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match page.lock().map(|p| p.clone()).unwrap_or_default() {
+            // The page is empty until the first refresh
+            p if p.is_empty() => ("503 Service Unavailable", "No data yet\n".to_string()),
+            p => ("200 OK", p),
+        },
+        (Some("GET"), _) => ("404 Not Found", "Not found. Metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+impl Exporter {
+    // Starts listening on the address, e.g. `127.0.0.1:9100`. Requests are served in a background thread.
+    pub(crate) fn start(addr: &str) -> io::Result<Exporter> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let page = Arc::new(Mutex::new(String::new()));
+        let shared = page.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // A client that has gone away must not stop the server
+                let _ = handle(stream, &shared);
+            }
+        });
+        Ok(Exporter { addr, page })
+    }
+
+    pub(crate) fn update(&self, procs: &[Process], mode: TitleMode, cpu_usage: u64, mem_usage: u64) {
+        let page = render(procs, mode, cpu_usage, mem_usage);
+        if let Ok(mut p) = self.page.lock() {
+            *p = page;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter::Sample;
+    use std::time::UNIX_EPOCH;
+    use sysinfo::{Pid, PidExt};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[test]
+    fn serve() {
+        let exp = Exporter::start("127.0.0.1:0").unwrap();
+        assert!(get(exp.addr, "/metrics").starts_with("HTTP/1.1 503 "));
+
+        let mut p = Process::new(Pid::from_u32(42), "sh -c \"x\"".to_string(), String::new(), String::new());
+        let s = Sample { cpu: 12, mem: 4096, io_r_total: 2, io_w_total: 1, ..Sample::default() };
+        // the first CPU value is always zero
+        p.add_sample(&s, UNIX_EPOCH);
+        p.add_sample(&s, UNIX_EPOCH);
+        p.dead = true;
+        exp.update(&[p], TitleMode::Cmd, 30, 40);
+
+        let resp = get(exp.addr, "/metrics");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = resp.split("\r\n\r\n").nth(1).unwrap();
+        let lines: Vec<&str> = body.lines().filter(|l| !l.starts_with('#')).collect();
+        let labels = "{pid=\"42\",title=\"sh -c \\\"x\\\"\"}";
+        assert_eq!(
+            lines,
+            vec![
+                "pwatch_cpu_usage_percent 30".to_string(),
+                "pwatch_mem_usage_percent 40".to_string(),
+                format!("pwatch_process_cpu_percent{} 12", labels),
+                format!("pwatch_process_resident_memory_bytes{} 4096", labels),
+                format!("pwatch_process_io_read_bytes_total{} 2048", labels),
+                format!("pwatch_process_io_write_bytes_total{} 1024", labels),
+                format!("pwatch_process_dead{} 1", labels),
+            ]
+        );
+
+        assert!(get(exp.addr, "/").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn reused_pid() {
+        let proc = |dead| {
+            let mut p = Process::new(Pid::from_u32(42), "srv".to_string(), String::new(), String::new());
+            p.dead = dead;
+            p
+        };
+        // the dead process is shadowed by the live one with the same PID and title
+        for procs in [vec![proc(true), proc(false)], vec![proc(false), proc(true)]].iter() {
+            let page = render(procs, TitleMode::Cmd, 0, 0);
+            let dead: Vec<&str> = page.lines().filter(|l| l.starts_with("pwatch_process_dead")).collect();
+            assert_eq!(dead, vec!["pwatch_process_dead{pid=\"42\",title=\"srv\"} 0"]);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ * Session recording. A record is a text file with one entry per line, fields are separated with TAB:
+ *   T <time> <total CPU%> <total MEM%>                      - the start of a refresh
+ *   P <time> <pid> <command line> <path to binary> <title>  - a new process
+ *   S <time> <pid> <CPU%> <MEM> <IO read> <IO write> <IO read delta> <IO write delta> - a sample
+ *   D <time> <pid> <exit status>                            - a process has exited
+ * Time is the number of milliseconds since UNIX epoch.
+ */
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sysinfo::{Pid, PidExt};
+
+use crate::counter::{Process, Sample};
+
+pub(crate) const HEADER: &str = "# pwatch record 1";
+
+pub(crate) struct Recorder {
+    out: BufWriter<File>,
+    known: HashSet<Pid>, // alive processes that have been already written to the record
+    exited: Vec<(Option<SystemTime>, Pid, String)>, // exits to write at the next refresh: time, PID, status
+    pub(crate) error: Option<io::Error>, // the first write error: recording stops after it
+}
+
+// Returns the number of milliseconds since UNIX epoch
+pub(crate) fn to_millis(tm: SystemTime) -> u128 {
+    match tm.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis(),
+        Err(_) => 0,
+    }
+}
+
+// Converts the number of milliseconds since UNIX epoch to time
+pub(crate) fn from_millis(ms: u128) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms as u64)
+}
+
+// Removes field and line separators from a string
+fn clean(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &str) -> io::Result<Recorder> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Recorder { out, known: HashSet::new(), exited: Vec::new(), error: None })
+    }
+
+    // Writes the latest samples of all alive processes and marks exited ones
+    pub(crate) fn tick(&mut self, tm: SystemTime, cpu_usage: u64, mem_usage: u64, procs: &[Process]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_tick(tm, cpu_usage, mem_usage, procs) {
+            self.error = Some(e);
+        }
+    }
+
+    // Remembers that a process has exited. It must be called before the process is revived by
+    // stitching: the revived process gets a new PID, and the old one would never be marked dead.
+    pub(crate) fn exited(&mut self, p: &Process) {
+        if self.known.remove(&p.pid) {
+            self.exited.push((p.dead_since, p.pid, p.exit_status.clone().unwrap_or_default()));
+        }
+    }
+
+    fn write_tick(&mut self, tm: SystemTime, cpu_usage: u64, mem_usage: u64, procs: &[Process]) -> io::Result<()> {
+        let ts = to_millis(tm);
+        writeln!(self.out, "T\t{}\t{}\t{}", ts, cpu_usage, mem_usage)?;
+        for p in procs.iter().filter(|p| p.dead) {
+            self.exited(p);
+        }
+        for (dead_since, pid, status) in std::mem::take(&mut self.exited) {
+            writeln!(self.out, "D\t{}\t{}\t{}", dead_since.map(to_millis).unwrap_or(ts), pid, status)?;
+        }
+        for p in procs.iter().filter(|p| !p.dead) {
+            if self.known.insert(p.pid) {
+                writeln!(self.out, "P\t{}\t{}\t{}\t{}\t{}", ts, p.pid, clean(&p.cmd), clean(&p.exe), clean(&p.title))?;
+            }
+            writeln!(
+                self.out,
+                "S\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                ts,
+                p.pid,
+                p.cpu.last(),
+                p.mem.last(),
+                p.io_r_total,
+                p.io_w_total,
+                p.io_r_delta,
+                p.io_w_delta
+            )?;
+        }
+        // Flush every refresh to keep the record usable if pwatch is killed
+        self.out.flush()
+    }
+}
+
+// A single entry of a recorded refresh
+pub(crate) enum Entry {
+    Proc { pid: Pid, cmd: String, exe: String, title: String },
+    Sample { pid: Pid, sample: Sample },
+    Dead { pid: Pid, ts: u128, status: String },
+}
+
+// All entries recorded during one refresh
+pub(crate) struct Tick {
+    pub(crate) ts: u128, // milliseconds since UNIX epoch
+    pub(crate) cpu_usage: u64,
+    pub(crate) mem_usage: u64,
+    pub(crate) entries: Vec<Entry>,
+}
+
+// Speed multipliers available during replay
+const SPEEDS: [f64; 9] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0];
+const NORMAL_SPEED: usize = 2;
+
+// Recorded session that is fed to the layout instead of live data
+pub(crate) struct Replay {
+    ticks: Vec<Tick>,
+    pos: usize, // index of the next tick to replay
+    speed: usize,
+    pub(crate) paused: bool,
+}
+
+fn parse_num<T: std::str::FromStr>(v: Option<&str>, lno: usize) -> Result<T, String> {
+    match v.map(|s| s.parse::<T>()) {
+        Some(Ok(n)) => Ok(n),
+        _ => Err(format!("line {}: invalid or missing number", lno)),
+    }
+}
+
+fn parse_str(v: Option<&str>) -> String {
+    v.unwrap_or("").to_string()
+}
+
+// Parses the content of a record file and returns the list of recorded refreshes
+fn parse(content: &str) -> Result<Vec<Tick>, String> {
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        return Err("not a pwatch record".to_string());
+    }
+    let mut ticks: Vec<Tick> = Vec::new();
+    for (idx, line) in lines.enumerate() {
+        let lno = idx + 2;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let kind = fields.next().unwrap_or("");
+        let ts: u128 = parse_num(fields.next(), lno)?;
+        if kind == "T" {
+            let cpu_usage = parse_num(fields.next(), lno)?;
+            let mem_usage = parse_num(fields.next(), lno)?;
+            ticks.push(Tick { ts, cpu_usage, mem_usage, entries: Vec::new() });
+            continue;
+        }
+        let pid = Pid::from_u32(parse_num(fields.next(), lno)?);
+        let entry = match kind {
+            "P" => {
+                let cmd = parse_str(fields.next());
+                let exe = parse_str(fields.next());
+                let title = parse_str(fields.next());
+                Entry::Proc { pid, cmd, exe, title }
+            }
+            "S" => {
+                let sample = Sample {
+                    cpu: parse_num(fields.next(), lno)?,
+                    mem: parse_num(fields.next(), lno)?,
+                    io_r_total: parse_num(fields.next(), lno)?,
+                    io_w_total: parse_num(fields.next(), lno)?,
+                    io_r_delta: parse_num(fields.next(), lno)?,
+                    io_w_delta: parse_num(fields.next(), lno)?,
+                };
+                Entry::Sample { pid, sample }
+            }
+            "D" => Entry::Dead { pid, ts, status: parse_str(fields.next()) },
+            _ => return Err(format!("line {}: unknown entry '{}'", lno, kind)),
+        };
+        match ticks.last_mut() {
+            Some(t) => t.entries.push(entry),
+            None => return Err(format!("line {}: entry before the first refresh", lno)),
+        }
+    }
+    Ok(ticks)
+}
+
+impl Replay {
+    pub(crate) fn load(path: &str) -> io::Result<Replay> {
+        let content = fs::read_to_string(path)?;
+        let ticks = parse(&content).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Replay { ticks, pos: 0, speed: NORMAL_SPEED, paused: false })
+    }
+
+    // Returns the next recorded refresh, or None if the whole record has been replayed
+    pub(crate) fn next_tick(&mut self) -> Option<&Tick> {
+        if self.pos >= self.ticks.len() {
+            return None;
+        }
+        self.pos += 1;
+        Some(&self.ticks[self.pos - 1])
+    }
+
+    // Returns the time of the latest replayed refresh
+    pub(crate) fn time(&self) -> Option<SystemTime> {
+        if self.pos == 0 {
+            return None;
+        }
+        Some(from_millis(self.ticks[self.pos - 1].ts))
+    }
+
+    // Returns the recorded time between the two latest replayed refreshes in milliseconds
+    pub(crate) fn recorded_interval(&self) -> Option<u64> {
+        if self.pos < 2 {
+            return None;
+        }
+        let diff = self.ticks[self.pos - 1].ts.saturating_sub(self.ticks[self.pos - 2].ts) as u64;
+        if diff == 0 {
+            None
+        } else {
+            Some(diff)
+        }
+    }
+
+    // Returns the average recorded time between refreshes in milliseconds over the whole record
+    pub(crate) fn average_interval(&self) -> Option<u64> {
+        let (first, last) = (self.ticks.first()?, self.ticks.last()?);
+        let diff = last.ts.saturating_sub(first.ts) as u64 / (self.ticks.len() as u64 - 1).max(1);
+        if diff == 0 {
+            None
+        } else {
+            Some(diff)
+        }
+    }
+
+    pub(crate) fn finished(&self) -> bool {
+        self.pos >= self.ticks.len()
+    }
+
+    // Returns the time to wait before the next refresh: the recorded time between refreshes
+    // divided by the replay speed. `freq` is used for the first refresh.
+    pub(crate) fn interval(&self, freq: u64) -> Duration {
+        let ms = if self.pos == 0 || self.pos >= self.ticks.len() {
+            freq
+        } else {
+            let diff = self.ticks[self.pos].ts.saturating_sub(self.ticks[self.pos - 1].ts);
+            diff as u64
+        };
+        Duration::from_millis((ms as f64 / SPEEDS[self.speed]) as u64)
+    }
+
+    pub(crate) fn speed(&self) -> f64 {
+        SPEEDS[self.speed]
+    }
+
+    // Changes the replay speed. Returns false if the speed is already at its limit.
+    pub(crate) fn change_speed(&mut self, faster: bool) -> bool {
+        if faster && self.speed + 1 < SPEEDS.len() {
+            self.speed += 1;
+            return true;
+        }
+        if !faster && self.speed > 0 {
+            self.speed -= 1;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use sysinfo::PidExt;
+
+    #[test]
+    fn record() {
+        let path = std::env::temp_dir().join(format!("pwatch-test-{}.rec", std::process::id()));
+        let spath = path.to_string_lossy().to_string();
+        let mut rec = Recorder::create(&spath).unwrap();
+        let mut p = Process::new(Pid::from_u32(42), "srv\t-v".to_string(), "/bin/srv".to_string(), "srv".to_string());
+        p.add(10, 2048);
+        p.io_r_total = 5;
+        let tm = UNIX_EPOCH + Duration::from_millis(1_000);
+        rec.tick(tm, 3, 40, &[p]);
+        let mut procs = vec![Process::new(Pid::from_u32(42), String::new(), String::new(), String::new())];
+        procs[0].dead = true;
+        procs[0].dead_since = Some(UNIX_EPOCH + Duration::from_millis(1_500));
+        procs[0].exit_status = Some("code 1".to_string());
+        rec.tick(tm + Duration::from_secs(1), 4, 41, &procs);
+        // dead process is written only once
+        rec.tick(tm + Duration::from_secs(2), 5, 42, &procs);
+        assert!(rec.error.is_none());
+        drop(rec);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                HEADER,
+                "T\t1000\t3\t40",
+                "P\t1000\t42\tsrv -v\t/bin/srv\tsrv",
+                "S\t1000\t42\t0\t2048\t5\t0\t0\t0",
+                "T\t2000\t4\t41",
+                "D\t1500\t42\tcode 1",
+                "T\t3000\t5\t42",
+            ]
+        );
+    }
+
+    #[test]
+    fn record_stitched() {
+        let path = std::env::temp_dir().join(format!("pwatch-test-stitch-{}.rec", std::process::id()));
+        let spath = path.to_string_lossy().to_string();
+        let mut rec = Recorder::create(&spath).unwrap();
+        let mut p = Process::new(Pid::from_u32(42), "srv".to_string(), String::new(), String::new());
+        p.add(1, 100);
+        let tm = UNIX_EPOCH + Duration::from_millis(1_000);
+        rec.tick(tm, 3, 40, std::slice::from_ref(&p));
+        // the process exits and is revived with a new PID before the next refresh is written
+        p.dead = true;
+        p.dead_since = Some(UNIX_EPOCH + Duration::from_millis(1_500));
+        p.exit_status = Some("code 1".to_string());
+        rec.exited(&p);
+        p.restart(Pid::from_u32(43));
+        p.add(0, 200);
+        rec.tick(tm + Duration::from_secs(1), 4, 41, &[p]);
+        drop(rec);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().skip(4).collect();
+        assert_eq!(
+            lines,
+            vec!["T\t2000\t4\t41", "D\t1500\t42\tcode 1", "P\t2000\t43\tsrv\t\t", "S\t2000\t43\t0\t200\t0\t0\t0\t0"]
+        );
+    }
+
+    #[test]
+    fn replay() {
+        assert!(parse("T\t1\t1\t1").is_err());
+        let content = format!("{}\nS\t1000\t42\t1\t2\t3\t4\t5\t6\n", HEADER);
+        assert!(parse(&content).is_err());
+        let content = format!("{}\nT\t1000\t3\t40\nX\t1000\t42\n", HEADER);
+        assert!(parse(&content).is_err());
+
+        let content = format!(
+            "{}\nT\t1000\t3\t40\nP\t1000\t42\tsrv -v\t/bin/srv\tsrv\nS\t1000\t42\t10\t2048\t5\t0\t0\t0\n\
+             T\t3000\t4\t41\nD\t2500\t42\tcode 1\n",
+            HEADER
+        );
+        let ticks = parse(&content).unwrap();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!((ticks[0].ts, ticks[0].cpu_usage, ticks[0].mem_usage), (1000, 3, 40));
+        assert_eq!(ticks[0].entries.len(), 2);
+        match ticks[0].entries[1] {
+            Entry::Sample { pid, sample } => {
+                assert_eq!(pid, Pid::from_u32(42));
+                assert_eq!((sample.cpu, sample.mem, sample.io_r_total), (10, 2048, 5));
+            }
+            _ => panic!("sample expected"),
+        }
+        match ticks[1].entries[0] {
+            Entry::Dead { pid, ts, ref status } => {
+                assert_eq!((pid, ts, status.as_str()), (Pid::from_u32(42), 2500, "code 1"))
+            }
+            _ => panic!("dead process expected"),
+        }
+
+        let mut rp = Replay { ticks, pos: 0, speed: NORMAL_SPEED, paused: false };
+        assert_eq!(rp.average_interval(), Some(2000));
+        assert_eq!(rp.interval(500), Duration::from_millis(500));
+        assert!(rp.next_tick().is_some());
+        assert_eq!(rp.recorded_interval(), None);
+        assert_eq!(rp.interval(500), Duration::from_millis(2000));
+        assert!(rp.change_speed(true));
+        assert_eq!(rp.interval(500), Duration::from_millis(1000));
+        assert!(rp.change_speed(false));
+        assert!(rp.change_speed(false));
+        assert!(rp.change_speed(false));
+        assert!(!rp.change_speed(false));
+        assert_eq!(rp.interval(500), Duration::from_millis(8000));
+        assert!(!rp.finished());
+        assert!(rp.next_tick().is_some());
+        assert_eq!(rp.recorded_interval(), Some(2000));
+        assert!(rp.finished());
+        assert!(rp.next_tick().is_none());
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Usage statistics(hotkey F5 and --summary): running totals and value buckets to estimate percentiles
+ */
+use std::collections::BTreeMap;
+
+// Summary statistics of a series of values
+#[derive(Debug, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) min: u64,
+    pub(crate) max: u64,
+    pub(crate) avg: f64,
+    pub(crate) p50: u64,
+    pub(crate) p95: u64,
+    pub(crate) p99: u64,
+    pub(crate) stddev: f64,
+}
+
+// Returns the lower bound of the histogram bucket of a value. Values below 128 have buckets of their
+// own, larger ones are grouped by their 7 most significant bits: a bucket is narrower than 1/64 of its values.
+fn bucket(val: u64) -> u64 {
+    if val < 128 {
+        return val;
+    }
+    let shift = 64 - val.leading_zeros() - 7;
+    (val >> shift) << shift
+}
+
+// Statistics of all values added since the start or the latest reset. The values are not kept, only
+// their number in every histogram bucket, so it covers a whole session of any length.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Running {
+    pub(crate) count: u64,
+    pub(crate) min: u64,
+    pub(crate) max: u64,
+    sum: f64,
+    sum_sq: f64,                 // sum of squares for the standard deviation
+    buckets: BTreeMap<u64, u64>, // number of values by the lower bound of their bucket
+}
+
+impl Running {
+    pub(crate) fn add(&mut self, val: u64) {
+        if self.count == 0 || val < self.min {
+            self.min = val;
+        }
+        if val > self.max {
+            self.max = val;
+        }
+        self.count += 1;
+        self.sum += val as f64;
+        self.sum_sq += (val as f64).powi(2);
+        *self.buckets.entry(bucket(val)).or_insert(0) += 1;
+    }
+
+    pub(crate) fn avg(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f64
+    }
+
+    pub(crate) fn stddev(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let avg = self.avg();
+        // rounding errors may make the variance slightly negative
+        (self.sum_sq / self.count as f64 - avg * avg).max(0.0).sqrt()
+    }
+
+    // Returns the value below which the given percent of values fall(nearest-rank method). It is
+    // estimated by the buckets: exact below 128, off by less than 1/64 above, and always within the
+    // minimum and maximum.
+    pub(crate) fn percentile(&self, pct: u64) -> u64 {
+        let rank = (pct * self.count).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (low, n) in self.buckets.iter() {
+            seen += n;
+            if seen >= rank {
+                return (*low).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+
+    // Returns statistics of all added values, or None if no value has been added
+    pub(crate) fn stats(&self) -> Option<Stats> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(Stats {
+            min: self.min,
+            max: self.max,
+            avg: self.avg(),
+            p50: self.percentile(50),
+            p95: self.percentile(95),
+            p99: self.percentile(99),
+            stddev: self.stddev(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running(values: &[u64]) -> Running {
+        let mut run = Running::default();
+        for v in values.iter() {
+            run.add(*v);
+        }
+        run
+    }
+
+    #[test]
+    fn stats() {
+        assert_eq!(Running::default().stats(), None);
+        let st = running(&[7]).stats().unwrap();
+        assert_eq!((st.min, st.max, st.p50, st.p99), (7, 7, 7, 7));
+        assert_eq!((st.avg, st.stddev), (7.0, 0.0));
+
+        let run = running(&[2, 4, 4, 4, 5, 5, 7, 9]);
+        assert_eq!((run.count, run.min, run.max), (8, 2, 9));
+        let st = run.stats().unwrap();
+        assert_eq!((st.min, st.max, st.p50, st.p95, st.p99), (2, 9, 4, 9, 9));
+        assert_eq!((st.avg, st.stddev), (5.0, 2.0));
+
+        let vals: Vec<u64> = (1..=100).rev().collect();
+        let st = running(&vals).stats().unwrap();
+        assert_eq!((st.p50, st.p95, st.p99), (50, 95, 99));
+    }
+
+    #[test]
+    fn estimated() {
+        assert_eq!((bucket(127), bucket(128), bucket(255), bucket(256), bucket(259)), (127, 128, 254, 256, 256));
+        // large values are estimated closely, and percentiles stay within the minimum and maximum
+        let vals: Vec<u64> = (0..1000).map(|i| 700_000_000 + i * 100_000).collect();
+        let st = running(&vals).stats().unwrap();
+        let exact = 700_000_000 + 499 * 100_000;
+        assert!(st.p50 <= exact && exact - st.p50 < exact / 64);
+        let st = running(&[1_000_001, 1_000_002]).stats().unwrap();
+        assert_eq!((st.min, st.p50, st.p99, st.max), (1_000_001, 1_000_001, 1_000_001, 1_000_002));
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * StatsD client(option --statsd). Every refresh sends gauges of alive processes over UDP:
+ *   <prefix>.cpu, <prefix>.mem, <prefix>.io_read, <prefix>.io_write
+ * Prefix is a template(option --statsd-prefix) where `{pid}` and `{title}` are replaced with
+ * the process PID and title. Memory and I/O are in bytes.
+ */
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use log::*;
+
+use crate::config::TitleMode;
+use crate::counter::Process;
+
+pub(crate) const DEFAULT_PREFIX: &str = "pwatch.{pid}";
+
+// Maximum size of a datagram that is not fragmented in most networks
+const MAX_PACKET: usize = 1400;
+
+pub(crate) struct StatsD {
+    socket: UdpSocket,
+    prefix: String,
+    failed: bool, // the last packet was not sent: log only the first error of a series
+}
+
+// Replaces characters that have special meaning in StatsD or in Graphite paths with underscores
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+// Returns the metric name prefix for a process
+fn metric_prefix(template: &str, p: &Process, mode: TitleMode) -> String {
+    template.replace("{pid}", &p.pid.to_string()).replace("{title}", &sanitize(&p.description(mode)))
+}
+
+// Joins lines into packets that do not exceed the maximum size
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    let mut cur = String::new();
+    for l in lines.iter() {
+        if !cur.is_empty() && cur.len() + l.len() + 1 > MAX_PACKET {
+            res.push(std::mem::take(&mut cur));
+        }
+        if !cur.is_empty() {
+            cur.push('\n');
+        }
+        cur += l;
+    }
+    if !cur.is_empty() {
+        res.push(cur);
+    }
+    res
+}
+
+impl StatsD {
+    // Creates a socket to send metrics to the StatsD daemon, e.g. `127.0.0.1:8125`
+    pub(crate) fn connect(addr: &str, prefix: &str) -> io::Result<StatsD> {
+        let target = match addr.to_socket_addrs()?.next() {
+            Some(a) => a,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "address not found")),
+        };
+        let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        Ok(StatsD { socket, prefix: prefix.to_string(), failed: false })
+    }
+
+    // Sends the latest values of all alive processes
+    pub(crate) fn send(&mut self, procs: &[Process], mode: TitleMode) {
+        let mut lines = Vec::new();
+        for p in procs.iter().filter(|p| !p.dead) {
+            let prefix = metric_prefix(&self.prefix, p, mode);
+            lines.push(format!("{}.cpu:{}|g", prefix, p.cpu.last()));
+            lines.push(format!("{}.mem:{}|g", prefix, p.mem.last()));
+            lines.push(format!("{}.io_read:{}|g", prefix, p.io_r_total * 1024));
+            lines.push(format!("{}.io_write:{}|g", prefix, p.io_w_total * 1024));
+        }
+        for packet in packets(&lines).iter() {
+            match self.socket.send(packet.as_bytes()) {
+                Ok(_) => self.failed = false,
+                Err(e) => {
+                    if !self.failed {
+                        error!("Failed to send metrics to StatsD: {}", e);
+                    }
+                    self.failed = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter::Sample;
+    use std::time::{Duration, UNIX_EPOCH};
+    use sysinfo::{Pid, PidExt};
+
+    #[test]
+    fn split() {
+        let lines: Vec<String> = (0..3).map(|_| "x".repeat(600)).collect();
+        let res = packets(&lines);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].len(), 1201);
+        assert!(packets(&[]).is_empty());
+    }
+
+    #[test]
+    fn send() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let mut client = StatsD::connect(&addr, "app.{title}.{pid}").unwrap();
+
+        let mut p = Process::new(Pid::from_u32(42), "my.app --port 80".to_string(), String::new(), String::new());
+        let s = Sample { cpu: 7, mem: 4096, io_r_total: 1, io_w_total: 2, ..Sample::default() };
+        // the first CPU value is always zero
+        p.add_sample(&s, UNIX_EPOCH);
+        p.add_sample(&s, UNIX_EPOCH);
+        let mut dead = Process::new(Pid::from_u32(7), "gone".to_string(), String::new(), String::new());
+        dead.dead = true;
+        client.send(&[p, dead], TitleMode::Cmd);
+
+        let mut buf = [0u8; 2048];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..n]),
+            "app.my_app_--port_80.42.cpu:7|g\n\
+             app.my_app_--port_80.42.mem:4096|g\n\
+             app.my_app_--port_80.42.io_read:1024|g\n\
+             app.my_app_--port_80.42.io_write:2048|g"
+        );
+    }
+}
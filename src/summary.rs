@@ -0,0 +1,178 @@
+/*
+ * End-of-run report: a table with one row per watched process, printed to stdout after pwatch exits
+ */
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+use unicode_width::UnicodeWidthStr;
+
+use crate::config::TitleMode;
+use crate::counter::Process;
+use crate::ux::{format_bytes, format_duration, format_mem_bytes};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Format {
+    Text,
+    Markdown,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+}
+
+// Column titles with alignment of the column in plain text: text is left-aligned, numbers are right-aligned
+const HEADER: [(&str, Align); 10] = [
+    ("PID", Align::Right),
+    ("TITLE", Align::Left),
+    ("LIFETIME", Align::Right),
+    ("PEAK CPU%", Align::Right),
+    ("AVG CPU%", Align::Right),
+    ("PEAK MEM", Align::Right),
+    ("AVG MEM", Align::Right),
+    ("READ", Align::Right),
+    ("WRITE", Align::Right),
+    ("EXITED", Align::Left),
+];
+
+// Returns cells of a table row for a process
+fn row(p: &Process, mode: TitleMode, now: SystemTime) -> Vec<String> {
+    let end = p.dead_since.unwrap_or(now);
+    let lifetime = match p.first_seen {
+        Some(st) => format_duration(end.duration_since(st).unwrap_or_default()),
+        None => "-".to_string(),
+    };
+    let exited = match p.dead_since {
+        None => String::new(),
+        Some(tm) => {
+            let tm: DateTime<Local> = tm.into();
+            let tm = tm.format("%Y-%m-%d %H:%M:%S").to_string();
+            match p.exit_status {
+                None => tm,
+                Some(ref st) => format!("{} ({})", tm, st),
+            }
+        }
+    };
+    // the graph values are reset by hotkey `r` and kept only for the history time, so use the whole session
+    let (peak_cpu, peak_mem) = p.peak();
+    let (avg_cpu, avg_mem) = p.session_avg();
+    let (io_r, io_w) = p.session_io();
+    vec![
+        p.pid.to_string(),
+        p.description(mode),
+        lifetime,
+        peak_cpu.to_string(),
+        (avg_cpu.round() as u64).to_string(),
+        format_mem_bytes(peak_mem),
+        format_mem_bytes(avg_mem.round() as u64),
+        format_bytes(io_r),
+        format_bytes(io_w),
+        exited,
+    ]
+}
+
+// Writes a table with lifetime, CPU, memory, and IO usage of every process
+pub(crate) fn write<W: Write>(
+    w: &mut W,
+    procs: &[Process],
+    mode: TitleMode,
+    fmt: Format,
+    now: SystemTime,
+) -> io::Result<()> {
+    let rows: Vec<Vec<String>> = procs.iter().map(|p| row(p, mode, now)).collect();
+    let header: Vec<String> = HEADER.iter().map(|(h, _)| h.to_string()).collect();
+    if fmt == Format::Markdown {
+        writeln!(w, "| {} |", header.join(" | "))?;
+        writeln!(w, "|{}", " --- |".repeat(HEADER.len()))?;
+        for r in rows.iter() {
+            let cells: Vec<String> = r.iter().map(|c| c.replace('|', "\\|")).collect();
+            writeln!(w, "| {} |", cells.join(" | "))?;
+        }
+        return Ok(());
+    }
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.width()).collect();
+    for r in rows.iter() {
+        for (wd, c) in widths.iter_mut().zip(r.iter()) {
+            *wd = (*wd).max(c.width());
+        }
+    }
+    for r in std::iter::once(&header).chain(rows.iter()) {
+        let mut line = String::new();
+        for ((c, wd), (_, align)) in r.iter().zip(widths.iter()).zip(HEADER.iter()) {
+            let pad = " ".repeat(wd - c.width());
+            if *align == Align::Left {
+                line += &format!("{}{}  ", c, pad);
+            } else {
+                line += &format!("{}{}  ", pad, c);
+            }
+        }
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter::Sample;
+    use std::time::{Duration, UNIX_EPOCH};
+    use sysinfo::{Pid, PidExt};
+
+    fn procs() -> Vec<Process> {
+        let mut p = Process::new(Pid::from_u32(42), "a|b".to_string(), String::new(), String::new());
+        // the first CPU sample is not a real usage value, so it does not count in the average
+        for (idx, cpu) in [90u64, 30, 60].iter().enumerate() {
+            let s = Sample { cpu: *cpu, mem: 2048 << 10, io_r_total: 2, io_w_total: 1, ..Sample::default() };
+            p.add_sample(&s, UNIX_EPOCH + Duration::from_secs(idx as u64));
+        }
+        let mut alive = Process::new(Pid::from_u32(7), "srv".to_string(), String::new(), String::new());
+        alive.add_sample(&Sample::default(), UNIX_EPOCH);
+        p.dead = true;
+        p.dead_since = Some(UNIX_EPOCH + Duration::from_secs(90));
+        p.exit_status = Some("code 1".to_string());
+        // a restarted process reports the IO of all its runs
+        let mut stitched = Process::new(Pid::from_u32(50), "db".to_string(), String::new(), String::new());
+        let io = |r, w| Sample { mem: 1024, io_r_total: r, io_w_total: w, ..Sample::default() };
+        stitched.add_sample(&io(100, 10), UNIX_EPOCH);
+        stitched.add_sample(&io(300, 20), UNIX_EPOCH + Duration::from_secs(1));
+        stitched.dead = true;
+        stitched.restart(Pid::from_u32(51));
+        stitched.add_sample(&io(5, 1), UNIX_EPOCH + Duration::from_secs(2));
+        stitched.add_sample(&io(700, 4), UNIX_EPOCH + Duration::from_secs(3));
+        vec![alive, p, stitched]
+    }
+
+    fn lines(fmt: Format) -> Vec<String> {
+        let mut out: Vec<u8> = Vec::new();
+        write(&mut out, &procs(), TitleMode::Cmd, fmt, UNIX_EPOCH + Duration::from_secs(10)).unwrap();
+        String::from_utf8(out).unwrap().lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn text() {
+        let l = lines(Format::Text);
+        assert_eq!(l.len(), 4);
+        assert!(l[0].starts_with("PID  TITLE  LIFETIME  PEAK CPU%  AVG CPU%  PEAK MEM"));
+        assert_eq!(l[1], "  7  srv         10s          0         0        0K       0K     0K     0K");
+        assert!(l[2].starts_with(" 42  a|b       1m30s         60        45        2M       2M     2K     1K  "));
+        assert!(l[2].ends_with("(code 1)"));
+        assert_eq!(l[3], " 51  db          10s          0         0        1K       1K  0.98M    24K");
+    }
+
+    #[test]
+    fn markdown() {
+        let l = lines(Format::Markdown);
+        assert_eq!(l.len(), 5);
+        assert_eq!(
+            l[0],
+            "| PID | TITLE | LIFETIME | PEAK CPU% | AVG CPU% | PEAK MEM | AVG MEM | READ | WRITE | EXITED |"
+        );
+        assert_eq!(l[1], "| --- | --- | --- | --- | --- | --- | --- | --- | --- | --- |");
+        assert_eq!(l[2], "| 7 | srv | 10s | 0 | 0 | 0K | 0K | 0K | 0K |  |");
+        assert!(l[3].starts_with("| 42 | a\\|b | 1m30s | 60 | 45 | 2M | 2M | 2K | 1K |"));
+        assert!(l[3].ends_with(" (code 1) |"));
+    }
+}
@@ -1,6 +1,7 @@
 /*
  * Set of utilities to convert or format data to make them user-friendlier
  */
+use std::process::ExitStatus;
 use std::time::Duration;
 
 use unicode_width::UnicodeWidthChar;
@@ -124,6 +125,30 @@ pub(crate) fn format_duration(d: Duration) -> String {
     }
 }
 
+// Parses a duration: a number with optional suffix `ms`, `s`(default), `m`, `h`, or `d`.
+// E.g., "90" and "1.5m" are both 90 seconds.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (num, mul) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 3600.0)
+    } else if let Some(n) = s.strip_suffix('d') {
+        (n, 86400.0)
+    } else {
+        (s, 1.0)
+    };
+    match num.parse::<f64>() {
+        // A value too big for Duration is an error, not a panic
+        Ok(v) if v >= 0.0 => Duration::try_from_secs_f64(v * mul).ok(),
+        _ => None,
+    }
+}
+
 // Converts difference in KB to string.
 pub(crate) fn format_diff(val: i64) -> String {
     let sgn = if val < 0 { '-' } else { '+' };
@@ -189,6 +214,31 @@ pub(crate) fn format_mem(val: u64) -> String {
     "!!!!!".to_string()
 }
 
+// Converts memory size in bytes, as sysinfo reports it, to string like `format_mem` does
+pub(crate) fn format_mem_bytes(val: u64) -> String {
+    format_mem(val / 1024)
+}
+
+// Converts memory difference in bytes to string like `format_diff` does
+pub(crate) fn format_diff_bytes(val: i64) -> String {
+    format_diff(val / 1024)
+}
+
+// Describes how a process finished: "code 1" for a normal exit, "signal 9" if it was killed.
+pub(crate) fn format_exit_status(st: ExitStatus) -> String {
+    if let Some(code) = st.code() {
+        return format!("code {}", code);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(sig) = st.signal() {
+            return format!("signal {}", sig);
+        }
+    }
+    "unknown".to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -252,6 +302,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn mem_fmt() {
+        let vals: [u64; 10] = [0, 67, 876, 1_000, 1_056, 2_048, 7_865, 784_670, 2_200_900, 7_777_555_444_222_333];
+        let ress: [&str; 10] = ["0K", "67K", "876K", "1000K", "1M", "2M", "7M", "766M", "2G", "!!!!!"];
+        for idx in 0..10usize {
+            let r = format_mem(vals[idx]);
+            assert_eq!(&r, ress[idx]);
+            let r = format_mem_bytes(vals[idx] * 1024 + 1023);
+            assert_eq!(&r, ress[idx]);
+        }
+        assert_eq!(format_diff_bytes(-2_048 * 1024), "-2M");
+        assert_eq!(format_diff_bytes(1023), "0K");
+    }
+
     #[test]
     fn short_round_test() {
         let vals: [u64; 9] = [0, 67, 876, 1_000, 1_056, 2_048, 7_865, 784_670, 2_200_900];
@@ -268,13 +332,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn duration_parse() {
+        let vals: [&str; 8] = ["0", "90", "1.5m", "250ms", "2h", "1d", " 30s ", "5s"];
+        let ress: [u64; 8] = [0, 90_000, 90_000, 250, 7_200_000, 86_400_000, 30_000, 5_000];
+        for (idx, v) in vals.iter().enumerate() {
+            let r = parse_duration(v).unwrap();
+            assert_eq!(r.as_millis() as u64, ress[idx]);
+        }
+        for v in ["", "m", "-5s", "10x", "1.2.3", "1e20", "1e18h", "inf", "NaN"].iter() {
+            assert!(parse_duration(v).is_none());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_status_fmt() {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(format_exit_status(ExitStatus::from_raw(0)), "code 0");
+        assert_eq!(format_exit_status(ExitStatus::from_raw(3 << 8)), "code 3");
+        assert_eq!(format_exit_status(ExitStatus::from_raw(9)), "signal 9");
+    }
+
     #[test]
     fn cut_test() {
         let orig = "0123456";
         let vals: [usize; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 8];
         let ress: [&str; 9] = ["0123456", "0", "01", "012", "0123", "01234", "012345", "0123456", "0123456"];
         for (idx, v) in vals.iter().enumerate() {
-            let r = cut_string(&orig, *v);
+            let r = cut_string(orig, *v);
             assert_eq!(&r, ress[idx]);
         }
     }